@@ -1,149 +1,1047 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 type TokenId = usize;
 
 pub struct SelfComposableModule {
     tokens: HashMap<TokenId, TokenData>,
+    next_token_id: TokenId,
+    composition_rules: Vec<CompositionRule>,
+    // Components held by each composite token, restored by decompose_token
+    compositions: HashMap<TokenId, Vec<(TokenId, TokenData)>>,
+    // Reverse index: token -> tokens whose self references or composable tokens point at it
+    referrers: HashMap<TokenId, HashSet<TokenId>>,
+    schemas: HashMap<String, TokenSchema>,
 }
 
-#[derive(Default)]
+// Typed value stored under a token attribute key
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttributeValue {
+    Bool(bool),
+    Int(i64),
+    UInt(u64),
+    Text(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FieldType {
+    Bool,
+    Int,
+    UInt,
+    Text,
+}
+
+impl AttributeValue {
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            AttributeValue::Bool(_) => FieldType::Bool,
+            AttributeValue::Int(_) => FieldType::Int,
+            AttributeValue::UInt(_) => FieldType::UInt,
+            AttributeValue::Text(_) => FieldType::Text,
+        }
+    }
+}
+
+// Named, typed fields allowed on tokens of a given kind
+#[derive(Clone, Debug)]
+pub struct TokenSchema {
+    pub kind: String,
+    pub fields: HashMap<String, FieldType>,
+}
+
+#[derive(Clone, Default)]
 pub struct TokenData {
-    // ... define fields here ...
+    pub attributes: HashMap<String, AttributeValue>,
+    pub amount: u64,
+    pub owner: String,
+    pub kind: String,
     composable_tokens: HashSet<TokenId>,
     self_references: HashSet<TokenId>,
+    origin: Option<TokenId>, // token this one was cloned from
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloneMode {
+    Shallow,  // copy the token, sharing its self references and composable tokens
+    Deep,     // copy the token and everything reachable through its self references
+    Template, // copy only the kind and owner, with no fields and an amount of 0
+}
+
+impl TokenData {
+    pub fn new(owner: String, amount: u64) -> Self {
+        Self {
+            owner,
+            amount,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_kind(mut self, kind: &str) -> Self {
+        self.kind = kind.to_string();
+        self
+    }
+
+    pub fn with_attribute(mut self, key: &str, value: AttributeValue) -> Self {
+        self.attributes.insert(key.to_string(), value);
+        self
+    }
+}
+
+// Declares which token kinds may be composed together and under what conditions
+#[derive(Clone, Debug)]
+pub struct CompositionRule {
+    pub name: String,
+    pub kinds: HashSet<String>, // every component must be one of these kinds
+    pub required_attributes: HashSet<String>, // every component must carry these attributes
+    pub max_components: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CompositionError {
+    TokenNotFound(TokenId),
+    DuplicateComponent(TokenId),
+    TooFewComponents,
+    OwnerMismatch(TokenId),
+    NotComposable(TokenId, TokenId),
+    NoMatchingRule(Vec<String>),
+    MissingAttribute { rule: String, token_id: TokenId, attribute: String },
+    TooManyComponents { rule: String, max: usize, found: usize },
+    NotComposite(TokenId),
+    Referenced { token_id: TokenId, referrers: Vec<TokenId> },
+    SameToken(TokenId),
+    Composite(TokenId),
+    AmountOverflow,
+}
+
+impl fmt::Display for CompositionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompositionError::TokenNotFound(id) => write!(f, "token {} does not exist", id),
+            CompositionError::DuplicateComponent(id) => write!(f, "token {} is listed more than once", id),
+            CompositionError::TooFewComponents => write!(f, "at least two tokens are needed to compose"),
+            CompositionError::OwnerMismatch(id) => write!(f, "token {} has a different owner", id),
+            CompositionError::NotComposable(a, b) => write!(f, "tokens {} and {} are not composable", a, b),
+            CompositionError::NoMatchingRule(kinds) => {
+                write!(f, "no composition rule allows kinds [{}]", kinds.join(", "))
+            }
+            CompositionError::MissingAttribute { rule, token_id, attribute } => write!(
+                f,
+                "rule '{}' violated: token {} is missing attribute '{}'",
+                rule, token_id, attribute
+            ),
+            CompositionError::TooManyComponents { rule, max, found } => write!(
+                f,
+                "rule '{}' violated: {} components exceed the maximum of {}",
+                rule, found, max
+            ),
+            CompositionError::NotComposite(id) => write!(f, "token {} is not a composite", id),
+            CompositionError::Referenced { token_id, referrers } => {
+                write!(f, "token {} is still referenced by tokens {:?}", token_id, referrers)
+            }
+            CompositionError::SameToken(id) => write!(f, "token {} cannot be merged with itself", id),
+            CompositionError::Composite(id) => write!(f, "token {} is a composite", id),
+            CompositionError::AmountOverflow => write!(f, "combined amount overflows"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReferenceError {
+    TokenNotFound(TokenId),
+    ReferenceNotFound(TokenId),
+    Referenced { token_id: TokenId, referrers: Vec<TokenId> },
+    Composite(TokenId),
+    HeldByComposite { token_id: TokenId, composite_id: TokenId },
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReferenceError::TokenNotFound(id) => write!(f, "token {} does not exist", id),
+            ReferenceError::ReferenceNotFound(id) => write!(f, "referenced token {} does not exist", id),
+            ReferenceError::Referenced { token_id, referrers } => {
+                write!(f, "token {} is still referenced by tokens {:?}", token_id, referrers)
+            }
+            ReferenceError::Composite(id) => write!(f, "token {} is a composite, decompose it first", id),
+            ReferenceError::HeldByComposite { token_id, composite_id } => {
+                write!(f, "token {} is held by composite {}", token_id, composite_id)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaError {
+    TokenNotFound(TokenId),
+    SchemaNotFound(String),
+    UnknownField { kind: String, field: String },
+    TypeMismatch { kind: String, field: String, expected: FieldType },
+    KindMismatch(TokenId, TokenId),
+    OwnerMismatch(TokenId),
+    ConflictingField(String),
+    Composite(TokenId),
+    SameToken(TokenId),
+    AmountOverflow,
+}
+
+impl fmt::Display for SchemaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaError::TokenNotFound(id) => write!(f, "token {} does not exist", id),
+            SchemaError::SchemaNotFound(kind) => write!(f, "no schema registered for kind '{}'", kind),
+            SchemaError::UnknownField { kind, field } => {
+                write!(f, "field '{}' is not declared in the schema for '{}'", field, kind)
+            }
+            SchemaError::TypeMismatch { kind, field, expected } => write!(
+                f,
+                "field '{}' of '{}' must be of type {:?}",
+                field, kind, expected
+            ),
+            SchemaError::KindMismatch(a, b) => write!(f, "tokens {} and {} are of different kinds", a, b),
+            SchemaError::OwnerMismatch(id) => write!(f, "token {} has a different owner", id),
+            SchemaError::ConflictingField(field) => write!(f, "field '{}' has conflicting values", field),
+            SchemaError::Composite(id) => write!(f, "token {} is a composite", id),
+            SchemaError::SameToken(id) => write!(f, "token {} cannot be joined with itself", id),
+            SchemaError::AmountOverflow => write!(f, "combined amount overflows"),
+        }
+    }
+}
+
+impl Default for SelfComposableModule {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SelfComposableModule {
     pub fn new() -> Self {
         Self {
             tokens: HashMap::new(),
+            next_token_id: 0,
+            composition_rules: Vec::new(),
+            compositions: HashMap::new(),
+            referrers: HashMap::new(),
+            schemas: HashMap::new(),
         }
     }
 
-    pub fn add_token(&mut self, token_id: TokenId, token_data: TokenData) {
-        self.tokens.insert(token_id, token_data);
+    pub fn register_schema(&mut self, schema: TokenSchema) {
+        self.schemas.insert(schema.kind.clone(), schema);
     }
 
-    pub fn add_self_reference(&mut self, token_id: TokenId, reference_id: TokenId) {
+    pub fn get_schema(&self, kind: &str) -> Option<&TokenSchema> {
+        self.schemas.get(kind)
+    }
+
+    // Sets a single attribute, checked against the schema for the token's kind if one is registered.
+    pub fn set_attribute(&mut self, token_id: TokenId, key: &str, value: AttributeValue) -> Result<(), SchemaError> {
+        let token_data = self.tokens.get(&token_id).ok_or(SchemaError::TokenNotFound(token_id))?;
+        if let Some(schema) = self.schemas.get(&token_data.kind) {
+            Self::check_field(schema, key, Some(&value))?;
+        }
         let token_data = self.tokens.get_mut(&token_id).unwrap();
+        token_data.attributes.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    pub fn add_composition_rule(&mut self, rule: CompositionRule) {
+        self.composition_rules.push(rule);
+    }
+
+    pub fn remove_composition_rule(&mut self, name: &str) {
+        self.composition_rules.retain(|rule| rule.name != name);
+    }
+
+    // Adds or replaces a token. Every token it references must already exist, and the ID
+    // must not belong to a composite or to a component held by one.
+    pub fn add_token(&mut self, token_id: TokenId, token_data: TokenData) -> Result<(), ReferenceError> {
+        if self.is_composite(token_id) {
+            return Err(ReferenceError::Composite(token_id));
+        }
+        if let Some(composite_id) = self.holding_composite(token_id) {
+            return Err(ReferenceError::HeldByComposite { token_id, composite_id });
+        }
+        for reference_id in token_data.self_references.iter().chain(&token_data.composable_tokens) {
+            if *reference_id != token_id && !self.tokens.contains_key(reference_id) {
+                return Err(ReferenceError::ReferenceNotFound(*reference_id));
+            }
+        }
+
+        // Keep generated IDs clear of IDs chosen by the caller
+        if token_id >= self.next_token_id {
+            self.next_token_id = token_id + 1;
+        }
+        self.take_token(token_id);
+        self.insert_token(token_id, token_data);
+        Ok(())
+    }
+
+    // Removes a token that no other token references.
+    pub fn remove_token(&mut self, token_id: TokenId) -> Result<TokenData, ReferenceError> {
+        if !self.tokens.contains_key(&token_id) {
+            return Err(ReferenceError::TokenNotFound(token_id));
+        }
+        if self.is_composite(token_id) {
+            return Err(ReferenceError::Composite(token_id));
+        }
+        let referrers = self.external_referrers(token_id, &[token_id]);
+        if !referrers.is_empty() {
+            return Err(ReferenceError::Referenced { token_id, referrers });
+        }
+        Ok(self.take_token(token_id).unwrap())
+    }
+
+    // Tokens whose self references or composable tokens point at token_id,
+    // including components held inside a composite.
+    pub fn find_referrers(&self, token_id: TokenId) -> HashSet<TokenId> {
+        self.referrers.get(&token_id).cloned().unwrap_or_default()
+    }
+
+    pub fn get_token(&self, token_id: TokenId) -> Option<&TokenData> {
+        self.tokens.get(&token_id)
+    }
+
+    pub fn add_self_reference(&mut self, token_id: TokenId, reference_id: TokenId) -> Result<(), ReferenceError> {
+        if !self.tokens.contains_key(&reference_id) {
+            return Err(ReferenceError::ReferenceNotFound(reference_id));
+        }
+        let token_data = self.tokens.get_mut(&token_id).ok_or(ReferenceError::TokenNotFound(token_id))?;
         token_data.self_references.insert(reference_id);
+        self.referrers.entry(reference_id).or_default().insert(token_id);
+        Ok(())
     }
 
-    pub fn remove_self_reference(&mut self, token_id: TokenId, reference_id: TokenId) {
-        let token_data = self.tokens.get_mut(&token_id).unwrap();
+    pub fn remove_self_reference(&mut self, token_id: TokenId, reference_id: TokenId) -> Result<(), ReferenceError> {
+        let token_data = self.tokens.get_mut(&token_id).ok_or(ReferenceError::TokenNotFound(token_id))?;
         token_data.self_references.remove(&reference_id);
+        if !token_data.composable_tokens.contains(&reference_id) {
+            self.unindex_reference(token_id, reference_id);
+        }
+        Ok(())
     }
 
     pub fn get_self_references(&self, token_id: TokenId) -> Option<HashSet<TokenId>> {
         self.tokens.get(&token_id).map(|token_data| token_data.self_references.clone())
     }
 
-    pub fn add_composable_token(&mut self, token_id: TokenId, composable_id: TokenId) {
-        let token_data = self.tokens.get_mut(&token_id).unwrap();
+    pub fn add_composable_token(&mut self, token_id: TokenId, composable_id: TokenId) -> Result<(), ReferenceError> {
+        if !self.tokens.contains_key(&composable_id) {
+            return Err(ReferenceError::ReferenceNotFound(composable_id));
+        }
+        let token_data = self.tokens.get_mut(&token_id).ok_or(ReferenceError::TokenNotFound(token_id))?;
         token_data.composable_tokens.insert(composable_id);
+        self.referrers.entry(composable_id).or_default().insert(token_id);
+        Ok(())
     }
 
-    pub fn remove_composable_token(&mut self, token_id: TokenId, composable_id: TokenId) {
-        let token_data = self.tokens.get_mut(&token_id).unwrap();
+    pub fn remove_composable_token(&mut self, token_id: TokenId, composable_id: TokenId) -> Result<(), ReferenceError> {
+        let token_data = self.tokens.get_mut(&token_id).ok_or(ReferenceError::TokenNotFound(token_id))?;
         token_data.composable_tokens.remove(&composable_id);
+        if !token_data.self_references.contains(&composable_id) {
+            self.unindex_reference(token_id, composable_id);
+        }
+        Ok(())
     }
 
     pub fn get_composable_tokens(&self, token_id: TokenId) -> Option<HashSet<TokenId>> {
         self.tokens.get(&token_id).map(|token_data| token_data.composable_tokens.clone())
     }
 
-    pub fn compose_tokens(&mut self, token_id1: TokenId, token_id2: TokenId) -> Option<TokenId> {
-        let mut new_token_data = TokenData::default();
-        let token_data1 = self.tokens.get(&token_id1)?;
-        let token_data2 = self.tokens.get(&token_id2)?;
+    // Bundles the components into a new composite token holding a single unit.
+    // All components must exist, share an owner, list each other as composable (in either
+    // direction) and satisfy at least one composition rule covering their kinds.
+    // The composite's kind is the name of the rule that allowed it, and the components
+    // are moved out of the live token set until decompose_token restores them, so no
+    // token outside the composition may reference them.
+    // The composite takes the union of their attributes (earlier components win on
+    // conflicting keys), composable tokens and self references.
+    pub fn compose_tokens(&mut self, component_ids: &[TokenId]) -> Result<TokenId, CompositionError> {
+        let rule_name = self.check_composition(component_ids)?;
 
-        // ... do some logic to compose the tokens ...
+        let components: Vec<(TokenId, TokenData)> = component_ids
+            .iter()
+            .map(|id| (*id, self.tokens.remove(id).unwrap()))
+            .collect();
 
-        let new_token_id = self.tokens.len() + 1; // or use a unique ID generator
-        self.tokens.insert(new_token_id, new_token_data);
+        // Components keep their outgoing references indexed while they are held
+        let mut new_token_data = TokenData::new(components[0].1.owner.clone(), 1).with_kind(&rule_name);
+        for (_, token_data) in components.iter().rev() {
+            new_token_data.attributes.extend(token_data.attributes.clone());
+            new_token_data.composable_tokens.extend(&token_data.composable_tokens);
+            new_token_data.self_references.extend(&token_data.self_references);
+        }
+        for id in component_ids {
+            new_token_data.composable_tokens.remove(id);
+            new_token_data.self_references.remove(id);
+        }
+
+        let new_token_id = self.generate_token_id();
+        self.insert_token(new_token_id, new_token_data);
+        self.compositions.insert(new_token_id, components);
 
-        Some(new_token_id)
+        Ok(new_token_id)
     }
 
-    pub fn merge_tokens(&mut self, token_id1: TokenId, token_id2: TokenId) -> TokenId {
-        let mut new_token_data = TokenData {
-            self_references: HashSet::new(),
-            // copy all fields from both tokens
-            //...
-        };
-        // merge self references
-        let token1_references = self.get_self_references(token_id1).unwrap();
-        let token2_references = self.get_self_references(token_id2).unwrap();
-        new_token_data.self_references.extend(token1_references);
-        new_token_data.self_references.extend(token2_references);
+    // Removes a composite token and restores its components under their original IDs.
+    // The composite must not be referenced by any other token.
+    pub fn decompose_token(&mut self, token_id: TokenId) -> Result<Vec<TokenId>, CompositionError> {
+        if !self.tokens.contains_key(&token_id) {
+            return Err(CompositionError::TokenNotFound(token_id));
+        }
+        if !self.is_composite(token_id) {
+            return Err(CompositionError::NotComposite(token_id));
+        }
+        let referrers = self.external_referrers(token_id, &[token_id]);
+        if !referrers.is_empty() {
+            return Err(CompositionError::Referenced { token_id, referrers });
+        }
+        let components = self.compositions.remove(&token_id).unwrap();
+        self.take_token(token_id);
+
+        let mut component_ids = Vec::new();
+        for (component_id, token_data) in components {
+            self.tokens.insert(component_id, token_data);
+            component_ids.push(component_id);
+        }
+        Ok(component_ids)
+    }
+
+    pub fn is_composite(&self, token_id: TokenId) -> bool {
+        self.compositions.contains_key(&token_id)
+    }
+
+    // The composite whose components include token_id, if any
+    pub fn holding_composite(&self, token_id: TokenId) -> Option<TokenId> {
+        self.compositions
+            .iter()
+            .find(|(_, components)| components.iter().any(|(id, _)| *id == token_id))
+            .map(|(composite_id, _)| *composite_id)
+    }
+
+    fn check_composition(&self, component_ids: &[TokenId]) -> Result<String, CompositionError> {
+        if component_ids.len() < 2 {
+            return Err(CompositionError::TooFewComponents);
+        }
+        let mut seen = HashSet::new();
+        let mut components = Vec::new();
+        for id in component_ids {
+            if !seen.insert(*id) {
+                return Err(CompositionError::DuplicateComponent(*id));
+            }
+            let token_data = self.tokens.get(id).ok_or(CompositionError::TokenNotFound(*id))?;
+            components.push((*id, token_data));
+        }
+        for id in component_ids {
+            let referrers = self.external_referrers(*id, component_ids);
+            if !referrers.is_empty() {
+                return Err(CompositionError::Referenced { token_id: *id, referrers });
+            }
+        }
+
+        let owner = &components[0].1.owner;
+        if let Some((id, _)) = components.iter().find(|(_, data)| &data.owner != owner) {
+            return Err(CompositionError::OwnerMismatch(*id));
+        }
+
+        for (i, (id1, data1)) in components.iter().enumerate() {
+            for (id2, data2) in &components[i + 1..] {
+                if !data1.composable_tokens.contains(id2) && !data2.composable_tokens.contains(id1) {
+                    return Err(CompositionError::NotComposable(*id1, *id2));
+                }
+            }
+        }
+
+        let kinds: Vec<String> = components.iter().map(|(_, data)| data.kind.clone()).collect();
+        let matching_rules: Vec<&CompositionRule> = self
+            .composition_rules
+            .iter()
+            .filter(|rule| kinds.iter().all(|kind| rule.kinds.contains(kind)))
+            .collect();
+
+        // The first matching rule that passes allows the composition; otherwise report
+        // the violation from the first rule that covered these kinds
+        let mut first_violation = None;
+        for rule in matching_rules {
+            match Self::check_rule(rule, &components) {
+                Ok(()) => return Ok(rule.name.clone()),
+                Err(err) => {
+                    first_violation.get_or_insert(err);
+                }
+            }
+        }
+        Err(first_violation.unwrap_or(CompositionError::NoMatchingRule(kinds)))
+    }
+
+    fn check_rule(rule: &CompositionRule, components: &[(TokenId, &TokenData)]) -> Result<(), CompositionError> {
+        if components.len() > rule.max_components {
+            return Err(CompositionError::TooManyComponents {
+                rule: rule.name.clone(),
+                max: rule.max_components,
+                found: components.len(),
+            });
+        }
+        for (id, data) in components {
+            for attribute in &rule.required_attributes {
+                if !data.attributes.contains_key(attribute) {
+                    return Err(CompositionError::MissingAttribute {
+                        rule: rule.name.clone(),
+                        token_id: *id,
+                        attribute: attribute.clone(),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces token_id1 and token_id2 with a single token holding their combined amount.
+    // Both tokens must have the same owner and neither may be a composite. Attributes, composable tokens and self references
+    // are unioned (token_id1 wins on conflicting attribute keys); references to either of
+    // the merged tokens are dropped from the result, and references to them held by other
+    // tokens are rewritten to point at the merged token.
+    pub fn merge_tokens(&mut self, token_id1: TokenId, token_id2: TokenId) -> Result<TokenId, CompositionError> {
+        if token_id1 == token_id2 {
+            return Err(CompositionError::SameToken(token_id1));
+        }
+        let token_data1 = self.tokens.get(&token_id1).ok_or(CompositionError::TokenNotFound(token_id1))?;
+        let token_data2 = self.tokens.get(&token_id2).ok_or(CompositionError::TokenNotFound(token_id2))?;
+        if token_data1.owner != token_data2.owner {
+            return Err(CompositionError::OwnerMismatch(token_id2));
+        }
+        for id in [token_id1, token_id2] {
+            if self.is_composite(id) {
+                return Err(CompositionError::Composite(id));
+            }
+        }
+        let amount = token_data1
+            .amount
+            .checked_add(token_data2.amount)
+            .ok_or(CompositionError::AmountOverflow)?;
+
+        let mut new_token_data = TokenData::new(token_data1.owner.clone(), amount).with_kind(&token_data1.kind);
+        new_token_data.attributes = token_data2.attributes.clone();
+        new_token_data.attributes.extend(token_data1.attributes.clone());
+
+        // merge composable tokens and self references
+        for token_data in [token_data1, token_data2] {
+            new_token_data.composable_tokens.extend(&token_data.composable_tokens);
+            new_token_data.self_references.extend(&token_data.self_references);
+        }
+        for merged_id in [token_id1, token_id2] {
+            new_token_data.composable_tokens.remove(&merged_id);
+            new_token_data.self_references.remove(&merged_id);
+        }
 
         // remove original tokens
-        self.tokens.remove(&token_id1);
-        self.tokens.remove(&token_id2);
+        self.take_token(token_id1);
+        self.take_token(token_id2);
 
-        // add merged token
-        let new_token_id = /* generate new ID */;
-        self.tokens.insert(new_token_id, new_token_data);
-        new_token_id
+        // add merged token and redirect references to the originals
+        let new_token_id = self.generate_token_id();
+        self.insert_token(new_token_id, new_token_data);
+        self.rewrite_references(token_id1, new_token_id);
+        self.rewrite_references(token_id2, new_token_id);
+        Ok(new_token_id)
     }
 
-    pub fn split_token(&mut self, token_id: TokenId, field_subset: HashSet<&str>) -> TokenId {
-        let original_token_data = self.tokens.get(&token_id).unwrap();
+    // Moves every field whose name is not in field_subset to a new token.
+    // The token's kind must have a registered schema; every name in field_subset and every
+    // field currently on the token must be declared there with a matching type.
+    // The original token keeps its ID, its amount and the fields in the subset;
+    // the new token has the same owner and kind, an amount of 0, and copies of the original's
+    // composable tokens and self references.
+    pub fn split_token(&mut self, token_id: TokenId, field_subset: HashSet<&str>) -> Result<TokenId, SchemaError> {
+        let original_token_data = self.tokens.get(&token_id).ok_or(SchemaError::TokenNotFound(token_id))?;
+        if self.is_composite(token_id) {
+            return Err(SchemaError::Composite(token_id));
+        }
+        let schema = self
+            .schemas
+            .get(&original_token_data.kind)
+            .ok_or_else(|| SchemaError::SchemaNotFound(original_token_data.kind.clone()))?;
+        for field in &field_subset {
+            Self::check_field(schema, field, None)?;
+        }
+        for (field, value) in &original_token_data.attributes {
+            Self::check_field(schema, field, Some(value))?;
+        }
+
+        let new_token_id = self.generate_token_id();
+        let original_token_data = self.tokens.get_mut(&token_id).unwrap();
 
         // create new token with fields not in subset
-        let mut new_token_data = TokenData {
-            self_references: original_token_data.self_references.clone(),
-            // copy all fields not in subset
-            //...
-        };
+        let mut new_token_data =
+            TokenData::new(original_token_data.owner.clone(), 0).with_kind(&original_token_data.kind);
+        new_token_data.composable_tokens = original_token_data.composable_tokens.clone();
+        new_token_data.self_references = original_token_data.self_references.clone();
 
         // modify original token data to only contain fields in subset
-        //...
-        
-        // remove original token
-        self.tokens.remove(&token_id);
-
-        // add new tokens
-        let original_token_id = /* generate original token ID */;
-        let new_token_id = /* generate new token ID */;
-        self.tokens.insert(original_token_id, original_token_data);
-        self.tokens.insert(new_token_id, new_token_data);
-        new_token_id
-    }
-
-    pub fn clone_token(&mut self, token_id: TokenId) -> Option<TokenId> {
-        let original_token_data = match self.tokens.get(&token_id) {
-            Some(data) => data,
-            None => return None, // Token doesn't exist
-        };
-    
-        // Create a new token with identical data
-        let mut new_token_data = TokenData {
-            // Copy all fields from the original token data
-            field1: original_token_data.field1.clone(),
-            field2: original_token_data.field2.clone(),
-            // ... add additional fields as needed ...
-            self_references: HashSet::new(),
-        };
-    
-        // Copy over all self-references
-        for reference_id in &original_token_data.self_references {
-            new_token_data.self_references.insert(*reference_id);
-        }
-    
-        // Generate a new token ID and insert it into the tokens map
-        let new_token_id = generate_token_id();
-        self.tokens.insert(new_token_id, new_token_data);
-    
-        Some(new_token_id)
-    }
-    
+        let moved_keys: Vec<String> = original_token_data
+            .attributes
+            .keys()
+            .filter(|key| !field_subset.contains(key.as_str()))
+            .cloned()
+            .collect();
+        for key in moved_keys {
+            let value = original_token_data.attributes.remove(&key).unwrap();
+            new_token_data.attributes.insert(key, value);
+        }
+
+        self.insert_token(new_token_id, new_token_data);
+        Ok(new_token_id)
+    }
+
+    // Recombines two tokens of the same kind and owner, typically the halves of a split,
+    // into token_id1. Fields present on both must hold equal values. Amounts are summed,
+    // composable tokens and self references are unioned, and references to token_id2
+    // are rewritten to point at token_id1.
+    pub fn join_tokens(&mut self, token_id1: TokenId, token_id2: TokenId) -> Result<TokenId, SchemaError> {
+        if token_id1 == token_id2 {
+            return Err(SchemaError::SameToken(token_id1));
+        }
+        let token_data1 = self.tokens.get(&token_id1).ok_or(SchemaError::TokenNotFound(token_id1))?;
+        let token_data2 = self.tokens.get(&token_id2).ok_or(SchemaError::TokenNotFound(token_id2))?;
+        if token_data1.kind != token_data2.kind {
+            return Err(SchemaError::KindMismatch(token_id1, token_id2));
+        }
+        if token_data1.owner != token_data2.owner {
+            return Err(SchemaError::OwnerMismatch(token_id2));
+        }
+        for id in [token_id1, token_id2] {
+            if self.is_composite(id) {
+                return Err(SchemaError::Composite(id));
+            }
+        }
+        let schema = self
+            .schemas
+            .get(&token_data1.kind)
+            .ok_or_else(|| SchemaError::SchemaNotFound(token_data1.kind.clone()))?;
+        for (field, value) in token_data1.attributes.iter().chain(&token_data2.attributes) {
+            Self::check_field(schema, field, Some(value))?;
+        }
+        for (field, value) in &token_data2.attributes {
+            if token_data1.attributes.get(field).is_some_and(|other| other != value) {
+                return Err(SchemaError::ConflictingField(field.clone()));
+            }
+        }
+        let amount = token_data1
+            .amount
+            .checked_add(token_data2.amount)
+            .ok_or(SchemaError::AmountOverflow)?;
+
+        let mut token_data1 = self.take_token(token_id1).unwrap();
+        let token_data2 = self.take_token(token_id2).unwrap();
+        token_data1.amount = amount;
+        token_data1.attributes.extend(token_data2.attributes);
+        token_data1.composable_tokens.extend(token_data2.composable_tokens);
+        token_data1.self_references.extend(token_data2.self_references);
+        token_data1.composable_tokens.remove(&token_id2);
+        token_data1.self_references.remove(&token_id2);
+
+        self.insert_token(token_id1, token_data1);
+        self.rewrite_references(token_id2, token_id1);
+        Ok(token_id1)
+    }
+
+    // Checks that field is declared in the schema and, when a value is given, that it has the declared type
+    fn check_field(schema: &TokenSchema, field: &str, value: Option<&AttributeValue>) -> Result<(), SchemaError> {
+        let expected = *schema.fields.get(field).ok_or_else(|| SchemaError::UnknownField {
+            kind: schema.kind.clone(),
+            field: field.to_string(),
+        })?;
+        match value {
+            Some(value) if value.field_type() != expected => Err(SchemaError::TypeMismatch {
+                kind: schema.kind.clone(),
+                field: field.to_string(),
+                expected,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Clones a token according to mode. Every clone records the token it was copied from
+    // as its origin. A deep clone copies each token reachable through self references once,
+    // remapping references inside the copied graph to the new IDs; references leaving the
+    // graph are shared. Returns the ID of the clone of token_id.
+    pub fn clone_token(&mut self, token_id: TokenId, mode: CloneMode) -> Option<TokenId> {
+        let original_token_data = self.tokens.get(&token_id)?;
+
+        match mode {
+            CloneMode::Shallow => {
+                let mut new_token_data = original_token_data.clone();
+                new_token_data.origin = Some(token_id);
+
+                let new_token_id = self.generate_token_id();
+                self.insert_token(new_token_id, new_token_data);
+                Some(new_token_id)
+            }
+            CloneMode::Template => {
+                let mut new_token_data =
+                    TokenData::new(original_token_data.owner.clone(), 0).with_kind(&original_token_data.kind);
+                new_token_data.origin = Some(token_id);
+
+                let new_token_id = self.generate_token_id();
+                self.insert_token(new_token_id, new_token_data);
+                Some(new_token_id)
+            }
+            CloneMode::Deep => {
+                // Collect the reference graph rooted at token_id
+                let mut id_map = HashMap::new();
+                let mut pending = vec![token_id];
+                while let Some(id) = pending.pop() {
+                    if id_map.contains_key(&id) {
+                        continue;
+                    }
+                    id_map.insert(id, self.generate_token_id());
+                    pending.extend(&self.tokens[&id].self_references);
+                }
+
+                let remap = |ids: &HashSet<TokenId>| -> HashSet<TokenId> {
+                    ids.iter().map(|id| *id_map.get(id).unwrap_or(id)).collect()
+                };
+                let mut clones = Vec::new();
+                for (original_id, new_id) in &id_map {
+                    let mut new_token_data = self.tokens[original_id].clone();
+                    new_token_data.self_references = remap(&new_token_data.self_references);
+                    new_token_data.composable_tokens = remap(&new_token_data.composable_tokens);
+                    new_token_data.origin = Some(*original_id);
+                    clones.push((*new_id, new_token_data));
+                }
+                for (new_id, new_token_data) in clones {
+                    self.insert_token(new_id, new_token_data);
+                }
+                Some(id_map[&token_id])
+            }
+        }
+    }
+
+    pub fn get_origin(&self, token_id: TokenId) -> Option<TokenId> {
+        self.tokens.get(&token_id).and_then(|token_data| token_data.origin)
+    }
+
     // Helper function to generate a unique token ID
-    fn generate_token_id() -> TokenId {
-        // ... implementation omitted ...
+    fn generate_token_id(&mut self) -> TokenId {
+        let token_id = self.next_token_id;
+        self.next_token_id += 1;
+        token_id
+    }
+
+    // Inserts a token and records it as a referrer of everything it points at
+    fn insert_token(&mut self, token_id: TokenId, token_data: TokenData) {
+        for reference_id in token_data.self_references.iter().chain(&token_data.composable_tokens) {
+            self.referrers.entry(*reference_id).or_default().insert(token_id);
+        }
+        self.tokens.insert(token_id, token_data);
+    }
+
+    // Removes a token along with its entries in the reverse index
+    fn take_token(&mut self, token_id: TokenId) -> Option<TokenData> {
+        let token_data = self.tokens.remove(&token_id)?;
+        for reference_id in token_data.self_references.iter().chain(&token_data.composable_tokens) {
+            self.unindex_reference(token_id, *reference_id);
+        }
+        Some(token_data)
+    }
+
+    fn unindex_reference(&mut self, token_id: TokenId, reference_id: TokenId) {
+        if let Some(referrers) = self.referrers.get_mut(&reference_id) {
+            referrers.remove(&token_id);
+            if referrers.is_empty() {
+                self.referrers.remove(&reference_id);
+            }
+        }
     }
-    
+
+    fn external_referrers(&self, token_id: TokenId, excluded: &[TokenId]) -> Vec<TokenId> {
+        let mut referrers: Vec<TokenId> = self
+            .find_referrers(token_id)
+            .into_iter()
+            .filter(|id| !excluded.contains(id))
+            .collect();
+        referrers.sort();
+        referrers
+    }
+
+    // Points every reference to old_id at new_id, including references held by components
+    fn rewrite_references(&mut self, old_id: TokenId, new_id: TokenId) {
+        let referrers = self.referrers.remove(&old_id).unwrap_or_default();
+        for referrer_id in referrers {
+            let token_data = match self.tokens.get_mut(&referrer_id) {
+                Some(data) => data,
+                None => self
+                    .compositions
+                    .values_mut()
+                    .flatten()
+                    .find(|(id, _)| *id == referrer_id)
+                    .map(|(_, data)| data)
+                    .unwrap(),
+            };
+            if token_data.self_references.remove(&old_id) {
+                token_data.self_references.insert(new_id);
+            }
+            if token_data.composable_tokens.remove(&old_id) {
+                token_data.composable_tokens.insert(new_id);
+            }
+            self.referrers.entry(new_id).or_default().insert(referrer_id);
+        }
+    }
+
     // ... add more functionality as needed ...
-    // MISSING --- GenerateTokenID API (for all interconnected modules) ,,, et cetera
     // very basic, very simple, very abstract
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gem_rule() -> CompositionRule {
+        CompositionRule {
+            name: "jewel".to_string(),
+            kinds: ["gem", "setting"].iter().map(|kind| kind.to_string()).collect(),
+            required_attributes: HashSet::new(),
+            max_components: 3,
+        }
+    }
+
+    fn gem_schema() -> TokenSchema {
+        let mut fields = HashMap::new();
+        fields.insert("color".to_string(), FieldType::Text);
+        fields.insert("carat".to_string(), FieldType::UInt);
+        TokenSchema {
+            kind: "gem".to_string(),
+            fields,
+        }
+    }
+
+    fn module_with(tokens: Vec<(TokenId, TokenData)>) -> SelfComposableModule {
+        let mut module = SelfComposableModule::new();
+        for (token_id, token_data) in tokens {
+            module.add_token(token_id, token_data).unwrap();
+        }
+        module
+    }
+
+    #[test]
+    fn compose_requires_mutual_composability_and_a_rule() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 1).with_kind("gem")),
+            (1, TokenData::new("alice".to_string(), 1).with_kind("setting")),
+        ]);
+        assert_eq!(module.compose_tokens(&[0, 1]), Err(CompositionError::NotComposable(0, 1)));
+
+        module.add_composable_token(0, 1).unwrap();
+        assert_eq!(
+            module.compose_tokens(&[0, 1]),
+            Err(CompositionError::NoMatchingRule(vec!["gem".to_string(), "setting".to_string()]))
+        );
+
+        module.add_composition_rule(gem_rule());
+        let composite_id = module.compose_tokens(&[0, 1]).unwrap();
+        assert!(module.is_composite(composite_id));
+        assert_eq!(module.get_token(composite_id).unwrap().kind, "jewel");
+        assert!(module.get_token(0).is_none());
+        assert!(module.get_token(1).is_none());
+    }
+
+    #[test]
+    fn compose_rejects_mixed_owners_and_duplicates() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 1).with_kind("gem")),
+            (1, TokenData::new("bob".to_string(), 1).with_kind("setting")),
+        ]);
+        module.add_composable_token(0, 1).unwrap();
+        module.add_composition_rule(gem_rule());
+        assert_eq!(module.compose_tokens(&[0]), Err(CompositionError::TooFewComponents));
+        assert_eq!(module.compose_tokens(&[0, 0]), Err(CompositionError::DuplicateComponent(0)));
+        assert_eq!(module.compose_tokens(&[0, 1]), Err(CompositionError::OwnerMismatch(1)));
+    }
+
+    #[test]
+    fn held_component_ids_cannot_be_reused() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 1).with_kind("gem")),
+            (1, TokenData::new("alice".to_string(), 1).with_kind("setting")),
+        ]);
+        module.add_composable_token(0, 1).unwrap();
+        module.add_composition_rule(gem_rule());
+        let composite_id = module.compose_tokens(&[0, 1]).unwrap();
+        assert_eq!(module.holding_composite(0), Some(composite_id));
+        assert_eq!(
+            module.add_token(0, TokenData::new("bob".to_string(), 9)),
+            Err(ReferenceError::HeldByComposite { token_id: 0, composite_id })
+        );
+
+        assert_eq!(module.decompose_token(composite_id), Ok(vec![0, 1]));
+        assert_eq!(module.get_token(0).unwrap().owner, "alice");
+        assert_eq!(module.holding_composite(0), None);
+        assert!(module.get_token(composite_id).is_none());
+    }
+
+    #[test]
+    fn composite_ids_cannot_be_replaced() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 1).with_kind("gem")),
+            (1, TokenData::new("alice".to_string(), 1).with_kind("setting")),
+        ]);
+        module.add_composable_token(0, 1).unwrap();
+        module.add_composition_rule(gem_rule());
+        let composite_id = module.compose_tokens(&[0, 1]).unwrap();
+        assert_eq!(
+            module.add_token(composite_id, TokenData::new("bob".to_string(), 9)),
+            Err(ReferenceError::Composite(composite_id))
+        );
+        assert_eq!(module.get_token(composite_id).unwrap().owner, "alice");
+        assert!(module.is_composite(composite_id));
+    }
+
+    #[test]
+    fn merge_sums_amounts_and_redirects_references() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 3).with_attribute("color", AttributeValue::Text("red".to_string()))),
+            (1, TokenData::new("alice".to_string(), 4).with_attribute("color", AttributeValue::Text("blue".to_string()))),
+            (2, TokenData::new("alice".to_string(), 1)),
+        ]);
+        module.add_self_reference(2, 1).unwrap();
+
+        let merged_id = module.merge_tokens(0, 1).unwrap();
+        let merged = module.get_token(merged_id).unwrap();
+        assert_eq!(merged.amount, 7);
+        assert_eq!(merged.attributes["color"], AttributeValue::Text("red".to_string()));
+        assert!(module.get_token(0).is_none());
+        assert!(module.get_token(1).is_none());
+        assert!(module.get_self_references(2).unwrap().contains(&merged_id));
+    }
+
+    #[test]
+    fn merge_reports_why_it_failed() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), u64::MAX)),
+            (1, TokenData::new("alice".to_string(), 1)),
+            (2, TokenData::new("bob".to_string(), 1)),
+        ]);
+        assert_eq!(module.merge_tokens(0, 0), Err(CompositionError::SameToken(0)));
+        assert_eq!(module.merge_tokens(0, 9), Err(CompositionError::TokenNotFound(9)));
+        assert_eq!(module.merge_tokens(1, 2), Err(CompositionError::OwnerMismatch(2)));
+        assert_eq!(module.merge_tokens(0, 1), Err(CompositionError::AmountOverflow));
+        assert!(module.get_token(0).is_some());
+        assert!(module.get_token(1).is_some());
+    }
+
+    #[test]
+    fn merge_rejects_composites() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 1).with_kind("gem")),
+            (1, TokenData::new("alice".to_string(), 1).with_kind("setting")),
+            (2, TokenData::new("alice".to_string(), 1)),
+        ]);
+        module.add_composable_token(0, 1).unwrap();
+        module.add_composition_rule(gem_rule());
+        let composite_id = module.compose_tokens(&[0, 1]).unwrap();
+        assert_eq!(module.merge_tokens(composite_id, 2), Err(CompositionError::Composite(composite_id)));
+    }
+
+    #[test]
+    fn split_moves_fields_outside_the_subset() {
+        let mut module = module_with(vec![(
+            0,
+            TokenData::new("alice".to_string(), 5)
+                .with_kind("gem")
+                .with_attribute("color", AttributeValue::Text("red".to_string()))
+                .with_attribute("carat", AttributeValue::UInt(2)),
+        )]);
+        module.register_schema(gem_schema());
+
+        let new_id = module.split_token(0, ["color"].into_iter().collect()).unwrap();
+        let original = module.get_token(0).unwrap();
+        let split = module.get_token(new_id).unwrap();
+        assert_eq!(original.amount, 5);
+        assert!(original.attributes.contains_key("color"));
+        assert!(!original.attributes.contains_key("carat"));
+        assert_eq!(split.amount, 0);
+        assert_eq!(split.kind, "gem");
+        assert_eq!(split.attributes["carat"], AttributeValue::UInt(2));
+    }
+
+    #[test]
+    fn split_checks_the_schema() {
+        let mut module = module_with(vec![(0, TokenData::new("alice".to_string(), 1).with_kind("gem"))]);
+        assert_eq!(
+            module.split_token(0, HashSet::new()),
+            Err(SchemaError::SchemaNotFound("gem".to_string()))
+        );
+        module.register_schema(gem_schema());
+        assert_eq!(
+            module.split_token(0, ["weight"].into_iter().collect()),
+            Err(SchemaError::UnknownField {
+                kind: "gem".to_string(),
+                field: "weight".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn join_recombines_split_halves() {
+        let mut module = module_with(vec![(
+            0,
+            TokenData::new("alice".to_string(), 5)
+                .with_kind("gem")
+                .with_attribute("color", AttributeValue::Text("red".to_string()))
+                .with_attribute("carat", AttributeValue::UInt(2)),
+        )]);
+        module.register_schema(gem_schema());
+        let new_id = module.split_token(0, ["color"].into_iter().collect()).unwrap();
+
+        assert_eq!(module.join_tokens(0, new_id), Ok(0));
+        let joined = module.get_token(0).unwrap();
+        assert_eq!(joined.amount, 5);
+        assert_eq!(joined.attributes.len(), 2);
+        assert!(module.get_token(new_id).is_none());
+    }
+
+    #[test]
+    fn join_reports_why_it_failed() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), u64::MAX).with_kind("gem")),
+            (1, TokenData::new("alice".to_string(), 1).with_kind("gem")),
+            (2, TokenData::new("alice".to_string(), 1).with_kind("setting")),
+            (3, TokenData::new("alice".to_string(), 1).with_kind("gem").with_attribute("carat", AttributeValue::UInt(1))),
+            (4, TokenData::new("alice".to_string(), 1).with_kind("gem").with_attribute("carat", AttributeValue::UInt(2))),
+        ]);
+        module.register_schema(gem_schema());
+        assert_eq!(module.join_tokens(0, 0), Err(SchemaError::SameToken(0)));
+        assert_eq!(module.join_tokens(0, 2), Err(SchemaError::KindMismatch(0, 2)));
+        assert_eq!(module.join_tokens(0, 1), Err(SchemaError::AmountOverflow));
+        assert_eq!(module.join_tokens(3, 4), Err(SchemaError::ConflictingField("carat".to_string())));
+        assert_eq!(module.get_token(0).unwrap().amount, u64::MAX);
+    }
+
+    #[test]
+    fn clone_modes_record_origin() {
+        let mut module = module_with(vec![
+            (0, TokenData::new("alice".to_string(), 1)),
+            (1, TokenData::new("alice".to_string(), 2).with_kind("gem").with_attribute("carat", AttributeValue::UInt(2))),
+        ]);
+        module.add_self_reference(1, 0).unwrap();
+
+        let shallow_id = module.clone_token(1, CloneMode::Shallow).unwrap();
+        assert_eq!(module.get_origin(shallow_id), Some(1));
+        assert_eq!(module.get_token(shallow_id).unwrap().amount, 2);
+        assert!(module.get_self_references(shallow_id).unwrap().contains(&0));
+
+        let template_id = module.clone_token(1, CloneMode::Template).unwrap();
+        let template = module.get_token(template_id).unwrap();
+        assert_eq!(module.get_origin(template_id), Some(1));
+        assert_eq!(template.amount, 0);
+        assert_eq!(template.kind, "gem");
+        assert!(template.attributes.is_empty());
+
+        let deep_id = module.clone_token(1, CloneMode::Deep).unwrap();
+        let deep_references = module.get_self_references(deep_id).unwrap();
+        assert_eq!(deep_references.len(), 1);
+        let copied_reference = *deep_references.iter().next().unwrap();
+        assert_ne!(copied_reference, 0);
+        assert_eq!(module.get_origin(copied_reference), Some(0));
+
+        assert_eq!(module.clone_token(9, CloneMode::Shallow), None);
+    }
+}
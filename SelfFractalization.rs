@@ -1,9 +1,1013 @@
+// SelfFractalizedSCTS777 and SCTS777 are separate contracts, each built to its own Wasm with
+// only its feature enabled (see build.sh). Items shared by both are unused in those builds.
+#![cfg_attr(not(all(feature = "self-fractalized", feature = "scts777")), allow(dead_code, unused_imports))]
+
+#[cfg(all(target_arch = "wasm32", feature = "self-fractalized", feature = "scts777"))]
+compile_error!("Build one contract per Wasm: enable either the self-fractalized or the scts777 feature");
+
 use near_sdk::{
+    assert_one_yocto,
     borsh::{self, BorshDeserialize, BorshSerialize},
-    collections::LookupMap,
-    env, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    collections::{LazyOption, LookupMap, LookupSet, UnorderedSet, Vector},
+    env, ext_contract,
+    json_types::{Base64VecU8, ValidAccountId, U128, U64},
+    near_bindgen,
+    serde::{Deserialize, Serialize},
+    serde_json::json,
+    AccountId, Balance, BorshStorageKey, Gas, IntoStorageKey, PanicOnDefault, Promise, PromiseOrValue, PromiseResult,
+    StorageUsage,
 };
+use std::collections::{HashMap, HashSet};
+
+// Storage prefixes for every collection in SCTS777 and SelfFractalizedSCTS777.
+// Nested per-account collections are keyed by the account ID hash, so prefixes
+// have a fixed length and cannot collide with each other.
+#[derive(BorshStorageKey, BorshSerialize)]
+pub enum StorageKey {
+    Balances,
+    Allowances,
+    AllowancesPerOwner { account_hash: Vec<u8> },
+    ChildTokens,
+    TokenOwners,
+    TokenBalances,
+    TokenBalancesPerOwner { account_hash: Vec<u8> },
+    TokenApprovals,
+    NextApprovalId,
+    GrandchildTokens,
+    GrandchildTokensPerParent { parent_token_id: u64 },
+    GrandchildTokenIds,
+    TokensPerOwner,
+    TokensPerOwnerInner { account_hash: Vec<u8> },
+    StorageBalances,
+    ChildCode,
+    LockedBalances,
+    RoleMembers,
+    RoleMembersPerRole { role: Role },
+    TokenMetadata,
+    FtHistory,
+    TokenHistory,
+    TokenHistoryPerToken { token_id: u64 },
+    BalanceIndices,
+    TokenUnlockTimes,
+    VestingSchedules,
+    ChildTokenAccounts,
+    TokenStoragePayers,
+    PendingChildTokens,
+    ChildTokensPerCreator,
+    ChildTokensPerCreatorInner { account_hash: Vec<u8> },
+}
+
+const NO_DEPOSIT: Balance = 0;
+const GAS_FOR_RESOLVE_TRANSFER: Gas = 5_000_000_000_000;
+const GAS_FOR_FT_TRANSFER_CALL: Gas = 25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER;
+const GAS_FOR_NFT_TRANSFER_CALL: Gas = 25_000_000_000_000 + GAS_FOR_RESOLVE_TRANSFER;
+const GAS_FOR_NFT_APPROVE: Gas = 10_000_000_000_000;
+const GAS_FOR_CHILD_INIT: Gas = 20_000_000_000_000;
+const GAS_FOR_CHILD_CALLBACK: Gas = 10_000_000_000_000;
+const GAS_FOR_BACKING_CALL: Gas = 10_000_000_000_000;
+// Batches stop before an item unless this much gas per token, plus the finishing reserve, is left
+const GAS_PER_BATCH_TOKEN: Gas = 5_000_000_000_000;
+const GAS_FOR_BATCH_FINISH: Gas = 15_000_000_000_000;
+const MAX_BATCH_MINT_COUNT: u64 = 20; // tokens per account in one mint item
+const GAS_FOR_MIGRATE: Gas = 100_000_000_000_000;
+
+// Records kept per history before the oldest are overwritten
+const MAX_FT_HISTORY_LEN: u64 = 1_000;
+const MAX_TOKEN_HISTORY_LEN: u64 = 20;
+
+// Fixed-point scale of the SCTS777 time index, where TIME_INDEX_SCALE means 1.0
+const TIME_INDEX_SCALE: u128 = 1_000_000_000_000;
+const BASIS_POINTS: u128 = 10_000;
+
+// Balance a child token account needs for its state on top of the code it stores (1 NEAR)
+const CHILD_STATE_DEPOSIT: Balance = 1_000_000_000_000_000_000_000_000;
+
+// SCTS777 follows ERC-777, where token amounts always use 18 decimals
+const FT_DECIMALS: u8 = 18;
+const FT_METADATA_SPEC: &str = "ft-1.0.0";
+
+// NEP-148 fungible token metadata
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FungibleTokenMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+    pub decimals: u8,
+}
+
+// NEP-141 receiver interface called by ft_transfer_call
+#[ext_contract(ext_fungible_token_receiver)]
+pub trait FungibleTokenReceiver {
+    fn ft_on_transfer(&mut self, sender_id: AccountId, amount: U128, msg: String) -> PromiseOrValue<U128>;
+}
+
+#[ext_contract(ext_self_fractalized_scts777)]
+pub trait SelfFractalizedSCTS777Callbacks {
+    fn on_child_token_created(&mut self, child_account_id: AccountId, creator_id: AccountId, backing: U128, deposit: U128) -> bool;
+    fn on_child_minted(&mut self, child_account_id: AccountId, owner_id: AccountId, amount: U128, ratio: U128) -> bool;
+    fn on_parent_released(&mut self, owner_id: AccountId, amount: U128) -> bool;
+    fn on_fractions_redeemed(&mut self, child_account_id: AccountId, owner_id: AccountId, amount: U128) -> U128;
+}
+
+// Calls a parent contract makes on its child tokens
+#[ext_contract(ext_child_token)]
+pub trait ChildTokenBacking {
+    fn mint_backed(&mut self, receiver_id: AccountId, amount: U128, ratio: U128);
+    fn burn_for_redeem(&mut self, owner_id: AccountId, amount: U128) -> U128;
+}
+
+// Calls a child contract makes on its parent token
+#[ext_contract(ext_parent_token)]
+pub trait ParentTokenBacking {
+    fn release_from_child(&mut self, receiver_id: AccountId, amount: U128);
+}
+
+#[ext_contract(ext_fungible_token_resolver)]
+pub trait FungibleTokenResolver {
+    fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128;
+}
+
+// Logs a NEP-297 event for contract events that are not covered by a token standard
+fn log_scts777_event(event: &str, data: near_sdk::serde_json::Value) {
+    let event = json!({
+        "standard": "scts777",
+        "version": "1.0.0",
+        "event": event,
+        "data": [data],
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+// Logs a NEP-297 event for the given NEP-141 event name and data
+fn log_ft_event(event: &str, data: near_sdk::serde_json::Value) {
+    let event = json!({
+        "standard": "nep141",
+        "version": "1.0.0",
+        "event": event,
+        "data": [data],
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+// NEP-177 contract-level metadata for grandchild tokens
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct NFTContractMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub base_uri: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+// Size limits for token metadata, in bytes unless stated otherwise
+const MAX_METADATA_TITLE_LEN: usize = 256;
+const MAX_METADATA_DESCRIPTION_LEN: usize = 4096;
+const MAX_METADATA_MEDIA_LEN: usize = 1024;
+const MAX_METADATA_ATTRIBUTES: usize = 32; // number of attributes
+const MAX_METADATA_ATTRIBUTE_KEY_LEN: usize = 64;
+const MAX_METADATA_ATTRIBUTE_VALUE_LEN: usize = 256;
+const METADATA_MEDIA_HASH_LEN: usize = 32; // sha256
+
+// NEP-177 token-level metadata
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+    pub copies: Option<u64>,
+    pub issued_at: Option<String>,
+    pub expires_at: Option<String>,
+    pub starts_at: Option<String>,
+    pub updated_at: Option<String>,
+    pub extra: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<Base64VecU8>,
+}
+
+// Metadata stored per SCTS777 token. Unset fields are inherited from the parent token.
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct StoredTokenMetadata {
+    title: Option<String>,
+    description: Option<String>,
+    media: Option<String>,
+    media_hash: Option<Vec<u8>>,
+    attributes: HashMap<String, String>,
+    parent_token_id: Option<u64>,
+    created_at: u64,
+    updated_at: u64,
+}
+
+// Metadata as returned by get_token_metadata, with inherited fields filled in
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadataView {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+    pub attributes: HashMap<String, String>,
+    pub parent_token_id: Option<u64>,
+    pub created_at: U64,
+    pub updated_at: U64,
+}
+
+// Changes to a token's metadata. Omitted fields are left as they are and an empty string
+// clears a field, so it is inherited again; clearing media also clears media_hash.
+// A null attribute value removes that attribute.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TokenMetadataPatch {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub media: Option<String>,
+    pub media_hash: Option<Base64VecU8>,
+    pub attributes: Option<HashMap<String, Option<String>>>,
+}
+
+// NEP-171 token view; grandchild token IDs are exposed as strings
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct JsonToken {
+    pub token_id: String,
+    pub owner_id: AccountId,
+    pub metadata: Option<TokenMetadata>,
+    pub approved_account_ids: Option<HashMap<AccountId, u64>>,
+}
+
+const NFT_METADATA_SPEC: &str = "nft-1.0.0";
+
+#[ext_contract(ext_non_fungible_token_receiver)]
+pub trait NonFungibleTokenReceiver {
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: String,
+        msg: String,
+    ) -> PromiseOrValue<bool>;
+}
+
+#[ext_contract(ext_non_fungible_token_resolver)]
+pub trait NonFungibleTokenResolver {
+    fn nft_resolve_transfer(
+        &mut self,
+        previous_owner_id: AccountId,
+        receiver_id: AccountId,
+        token_id: String,
+        approved_account_ids: Option<HashMap<AccountId, u64>>,
+    ) -> bool;
+}
+
+#[ext_contract(ext_non_fungible_approval_receiver)]
+pub trait NonFungibleTokenApprovalReceiver {
+    fn nft_on_approve(&mut self, token_id: String, owner_id: AccountId, approval_id: u64, msg: String);
+}
+
+// Logs a NEP-297 event for the given NEP-171 event name and data
+fn log_nft_event(event: &str, data: near_sdk::serde_json::Value) {
+    log_nft_events(event, vec![data]);
+}
+
+// Logs a single NEP-297 event covering several NEP-171 data entries
+fn log_nft_events(event: &str, data: Vec<near_sdk::serde_json::Value>) {
+    let event = json!({
+        "standard": "nep171",
+        "version": "1.0.0",
+        "event": event,
+        "data": data,
+    });
+    env::log(format!("EVENT_JSON:{}", event).as_bytes());
+}
+
+// Outcome of one item of a grandchild token batch
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde", tag = "status", rename_all = "snake_case")]
+pub enum BatchItemResult {
+    Ok { token_ids: Vec<String> },
+    Failed { reason: String },
+}
+
+// Results of a grandchild token batch, one per processed item. A batch that runs low on gas
+// stops early; `next_index` is then the first item left to resubmit.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BatchResult {
+    pub results: Vec<BatchItemResult>,
+    pub next_index: Option<u64>,
+}
+
+// Returns whether there is gas left to process `tokens` more tokens and finish the batch
+fn batch_has_gas_for(tokens: u64) -> bool {
+    env::used_gas() + tokens * GAS_PER_BATCH_TOKEN + GAS_FOR_BATCH_FINISH <= env::prepaid_gas()
+}
+
+// NEP-145 storage balance of an account
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalance {
+    pub total: U128,
+    pub available: U128,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StorageBalanceBounds {
+    pub min: U128,
+    pub max: Option<U128>,
+}
+
+// What an account has deposited for storage and how many bytes of contract state it pays for
+#[derive(BorshSerialize, BorshDeserialize, Default)]
+pub struct AccountStorage {
+    pub deposit: Balance,
+    pub used_bytes: StorageUsage,
+}
+
+// The account charged for a token's storage when it was minted and how many bytes it paid
+// for, followed by the bytes other accounts were charged later for its metadata or lock
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct TokenStorage {
+    pub payer_id: AccountId,
+    pub used_bytes: StorageUsage,
+    pub added_bytes: Vec<(AccountId, StorageUsage)>,
+}
+
+fn parse_token_id(token_id: &str) -> u64 {
+    token_id.parse().expect("Invalid token ID")
+}
+
+// Roles that can be granted on top of the contract owner, who implicitly holds all of them.
+// Only the owner can grant or revoke Admin; the owner and admins manage the other roles.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Role {
+    Admin,
+    Pauser,
+    Minter,
+    MetadataManager,
+}
+
+// Implements roles and two-step ownership transfer for a contract with `owner_id`,
+// `pending_owner_id` and `role_members` fields.
+macro_rules! impl_access_control {
+    ($contract: ident) => {
+        #[near_bindgen]
+        impl $contract {
+            pub fn get_owner(&self) -> AccountId {
+                self.owner_id.clone()
+            }
+
+            pub fn get_pending_owner(&self) -> Option<AccountId> {
+                self.pending_owner_id.clone()
+            }
+
+            // Proposes a new owner, who becomes the owner once they call accept_ownership.
+            // Proposing again replaces the pending owner; None cancels the transfer.
+            pub fn transfer_ownership(&mut self, new_owner_id: Option<ValidAccountId>) {
+                self.assert_owner();
+                self.pending_owner_id = new_owner_id.map(|account_id| account_id.into());
+                log_scts777_event(
+                    "ownership_transfer_started",
+                    json!({ "owner_id": self.owner_id, "pending_owner_id": self.pending_owner_id }),
+                );
+            }
+
+            pub fn accept_ownership(&mut self) {
+                let new_owner_id = env::predecessor_account_id();
+                assert_eq!(
+                    Some(new_owner_id.clone()),
+                    self.pending_owner_id,
+                    "Only the pending owner can accept ownership"
+                );
+                let previous_owner_id = std::mem::replace(&mut self.owner_id, new_owner_id);
+                self.pending_owner_id = None;
+                log_scts777_event(
+                    "ownership_transferred",
+                    json!({ "previous_owner_id": previous_owner_id, "owner_id": self.owner_id }),
+                );
+            }
+
+            // Returns whether the account holds the role itself; the owner is not listed
+            pub fn has_role(&self, role: Role, account_id: ValidAccountId) -> bool {
+                self.role_members
+                    .get(&role)
+                    .map_or(false, |members| members.contains(account_id.as_ref()))
+            }
+
+            pub fn get_role_members(&self, role: Role) -> Vec<AccountId> {
+                self.role_members.get(&role).map_or_else(Vec::new, |members| members.to_vec())
+            }
+
+            pub fn grant_role(&mut self, role: Role, account_id: ValidAccountId) {
+                self.assert_role_manager(role);
+                let account_id: AccountId = account_id.into();
+                let mut members = self
+                    .role_members
+                    .get(&role)
+                    .unwrap_or_else(|| UnorderedSet::new(StorageKey::RoleMembersPerRole { role }));
+                if members.insert(&account_id) {
+                    self.role_members.insert(&role, &members);
+                    log_scts777_event(
+                        "role_granted",
+                        json!({ "role": role, "account_id": account_id, "sender_id": env::predecessor_account_id() }),
+                    );
+                }
+            }
+
+            pub fn revoke_role(&mut self, role: Role, account_id: ValidAccountId) {
+                self.assert_role_manager(role);
+                self.internal_remove_role(role, account_id.as_ref());
+            }
+
+            // Gives up a role the caller holds
+            pub fn renounce_role(&mut self, role: Role) {
+                self.internal_remove_role(role, &env::predecessor_account_id());
+            }
+
+            fn internal_remove_role(&mut self, role: Role, account_id: &AccountId) {
+                if let Some(mut members) = self.role_members.get(&role) {
+                    if members.remove(account_id) {
+                        self.role_members.insert(&role, &members);
+                        log_scts777_event(
+                            "role_revoked",
+                            json!({ "role": role, "account_id": account_id, "sender_id": env::predecessor_account_id() }),
+                        );
+                    }
+                }
+            }
+
+            fn assert_owner(&self) {
+                assert_eq!(
+                    env::predecessor_account_id(),
+                    self.owner_id,
+                    "This function can only be called by the contract owner"
+                );
+            }
+
+            // Asserts that the caller is the owner or holds `role`
+            fn assert_role(&self, role: Role) {
+                let caller_id = env::predecessor_account_id();
+                if caller_id != self.owner_id
+                    && !self.role_members.get(&role).map_or(false, |members| members.contains(&caller_id))
+                {
+                    env::panic(format!("This function requires the {:?} role", role).as_bytes());
+                }
+            }
+
+            fn assert_role_manager(&self, role: Role) {
+                match role {
+                    Role::Admin => self.assert_owner(),
+                    _ => self.assert_role(Role::Admin),
+                }
+            }
+        }
+    };
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum TransactionReason {
+    Mint,
+    Transfer,
+    Burn,
+    Lock,    // moved into the backing locked for a child token or into vesting escrow
+    Release, // released from child token backing or from vesting escrow
+}
+
+// A history record. Mints have no sender and burns have no receiver.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Transaction {
+    pub from: Option<AccountId>,
+    pub to: Option<AccountId>,
+    pub amount: U128,
+    pub timestamp: U64,
+    pub block_height: U64,
+    pub reason: TransactionReason,
+}
+
+impl Transaction {
+    fn new(from: Option<&AccountId>, to: Option<&AccountId>, amount: Balance, reason: TransactionReason) -> Self {
+        Self {
+            from: from.cloned(),
+            to: to.cloned(),
+            amount: U128(amount),
+            timestamp: U64(env::block_timestamp()),
+            block_height: U64(env::block_index()),
+            reason,
+        }
+    }
+}
+
+// A vesting allocation of SelfFractalizedSCTS777 tokens escrowed by the grantor. Nothing
+// vests before `start + cliff`; after that `total` vests linearly from `start` until
+// `start + duration`. Revoking ends vesting and cuts `total` down to what had vested.
+// The grantor's `storage_deposit` pays for the schedule until nothing is left to claim.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct VestingSchedule {
+    pub grantor_id: AccountId,
+    pub beneficiary_id: AccountId,
+    pub total: U128,
+    pub claimed: U128,
+    pub start: U64,    // timestamp in nanoseconds
+    pub cliff: U64,    // nanoseconds after start
+    pub duration: U64, // nanoseconds after start
+    pub revocable: bool,
+    pub revoked: bool,
+    pub storage_deposit: U128,
+}
+
+impl VestingSchedule {
+    fn vested_at(&self, timestamp: u64) -> Balance {
+        if self.revoked {
+            return self.total.0;
+        }
+        let elapsed = timestamp.saturating_sub(self.start.0);
+        if elapsed < self.cliff.0 {
+            0
+        } else if elapsed >= self.duration.0 {
+            self.total.0
+        } else {
+            mul_div(self.total.0, u128::from(elapsed), u128::from(self.duration.0)).expect("Vesting overflow")
+        }
+    }
+}
+
+// A child token registered by SelfFractalizedSCTS777
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ChildTokenView {
+    pub child_token_id: u64,
+    pub account_id: AccountId,
+    pub locked_balance: U128, // parent tokens backing the child's supply
+}
+
+// A list that can be read by position, such as a vector or a transfer history
+trait IndexedList<T> {
+    fn len(&self) -> u64;
+    fn get(&self, index: u64) -> Option<T>;
+}
+
+impl<T: BorshSerialize + BorshDeserialize> IndexedList<T> for Vector<T> {
+    fn len(&self) -> u64 {
+        Vector::len(self)
+    }
+
+    fn get(&self, index: u64) -> Option<T> {
+        Vector::get(self, index)
+    }
+}
+
+// Returns up to `limit` items of a list starting at `from_index`
+fn paginate<T>(items: &impl IndexedList<T>, from_index: Option<U128>, limit: Option<u64>) -> Vec<T> {
+    let start = u128::from(from_index.unwrap_or(U128(0))).min(u128::from(items.len())) as u64;
+    let end = start.saturating_add(limit.unwrap_or(u64::MAX)).min(items.len());
+    (start..end).filter_map(|index| items.get(index)).collect()
+}
 
+// Returns `a * b / c` rounded down, using a 256-bit intermediate product so that only the
+// result has to fit in a u128. Returns None if `c` is zero or the result overflows.
+fn mul_div(a: u128, b: u128, c: u128) -> Option<u128> {
+    if c == 0 {
+        return None;
+    }
+    const LOW_MASK: u128 = u64::MAX as u128;
+    let (a_high, a_low) = (a >> 64, a & LOW_MASK);
+    let (b_high, b_low) = (b >> 64, b & LOW_MASK);
+    let low_low = a_low * b_low;
+    let high_low = a_high * b_low;
+    let low_high = a_low * b_high;
+    let middle = (low_low >> 64) + (high_low & LOW_MASK) + (low_high & LOW_MASK);
+    let low = (middle << 64) | (low_low & LOW_MASK);
+    let high = a_high * b_high + (high_low >> 64) + (low_high >> 64) + (middle >> 64);
+    if high >= c {
+        return None;
+    }
+    // Long division of (high, low) by c, one bit at a time; the remainder stays below c
+    let (mut quotient, mut remainder) = (0u128, high);
+    for bit in (0..128).rev() {
+        let carry = remainder >> 127;
+        remainder = (remainder << 1) | ((low >> bit) & 1);
+        quotient <<= 1;
+        if carry == 1 || remainder >= c {
+            remainder = remainder.wrapping_sub(c);
+            quotient |= 1;
+        }
+    }
+    Some(quotient)
+}
+
+// Time-based currency settings of SCTS777. Balances grow (positive rate) or shrink
+// (negative rate) linearly by `rate_bps` basis points per `period` nanoseconds, compounding
+// whenever the rate is changed. `index` is the growth factor accumulated up to `updated_at`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TimeRate {
+    pub rate_bps: i32,
+    pub period: U64,
+    pub index: U128,
+    pub updated_at: U64,
+}
+
+impl TimeRate {
+    // Returns the time index at `timestamp`, which never drops below 1 so that balances
+    // can always be rescaled
+    fn index_at(&self, timestamp: u64) -> u128 {
+        let elapsed = u128::from(timestamp.saturating_sub(self.updated_at.0));
+        let change = mul_div(
+            self.index.0,
+            u128::from(self.rate_bps.unsigned_abs()) * elapsed,
+            BASIS_POINTS * u128::from(self.period.0),
+        )
+        .expect("Time index overflow");
+        if self.rate_bps >= 0 {
+            self.index.0.checked_add(change).expect("Time index overflow")
+        } else {
+            self.index.0.saturating_sub(change).max(1)
+        }
+    }
+}
+
+// The most recent `capacity` records of a transfer history, kept as a ring buffer so that
+// the storage a history takes is bounded. Records are indexed from the oldest one kept.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub struct History {
+    records: Vector<Transaction>,
+    capacity: u64,
+    recorded: u64, // records ever pushed; once full, the oldest kept record is at recorded % capacity
+}
+
+impl History {
+    fn new<S: IntoStorageKey>(prefix: S, capacity: u64) -> Self {
+        Self {
+            records: Vector::new(prefix),
+            capacity,
+            recorded: 0,
+        }
+    }
+
+    // Appends a record, overwriting the oldest one once the history is full
+    fn push(&mut self, record: &Transaction) {
+        if self.records.len() < self.capacity {
+            self.records.push(record);
+        } else {
+            self.records.replace(self.recorded % self.capacity, record);
+        }
+        self.recorded += 1;
+    }
+}
+
+impl IndexedList<Transaction> for History {
+    fn len(&self) -> u64 {
+        self.records.len()
+    }
+
+    fn get(&self, index: u64) -> Option<Transaction> {
+        if index >= self.records.len() {
+            return None;
+        }
+        let oldest = if self.records.len() < self.capacity { 0 } else { self.recorded % self.capacity };
+        self.records.get((oldest + index) % self.capacity)
+    }
+}
+
+// Implements the fungible token history for a contract with an `ft_history` field and the
+// feature registry. Nothing is recorded while the History feature is inactive, and only the
+// last MAX_FT_HISTORY_LEN records are kept.
+macro_rules! impl_ft_history {
+    ($contract: ident) => {
+        #[near_bindgen]
+        impl $contract {
+            pub fn get_ft_history(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<Transaction> {
+                paginate(&self.ft_history, from_index, limit)
+            }
+
+            pub fn get_ft_history_length(&self) -> U64 {
+                U64(self.ft_history.len())
+            }
+
+            fn internal_record_ft(
+                &mut self,
+                from: Option<&AccountId>,
+                to: Option<&AccountId>,
+                amount: Balance,
+                reason: TransactionReason,
+            ) {
+                if self.active_features.contains(&Feature::History) {
+                    self.ft_history.push(&Transaction::new(from, to, amount, reason));
+                }
+            }
+        }
+    };
+}
+
+// Capabilities that can be switched on and off per deployment. Every feature is active
+// after initialization; entry points that open new positions check their feature, while
+// exits such as redeeming and burning always stay available.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum Feature {
+    GrandchildMinting,  // generate_grandchild_token
+    SelfFractalization, // create_child_token, fractionalize
+    Metadata,           // token metadata updates
+    History,            // recording transfer history
+    Composability,      // ft_transfer_call and nft_transfer_call
+}
+
+impl Feature {
+    const ALL: [Feature; 5] = [
+        Feature::GrandchildMinting,
+        Feature::SelfFractalization,
+        Feature::Metadata,
+        Feature::History,
+        Feature::Composability,
+    ];
+}
+
+// Implements the feature registry for a contract with an `active_features` field and
+// access control
+macro_rules! impl_feature_flags {
+    ($contract: ident) => {
+        #[near_bindgen]
+        impl $contract {
+            // Activates a feature and returns whether it was inactive. Requires the Admin role.
+            pub fn activate(&mut self, feature: Feature) -> bool {
+                self.assert_role(Role::Admin);
+                let activated = self.active_features.insert(feature);
+                if activated {
+                    log_scts777_event("feature_activated", json!({ "feature": feature }));
+                }
+                activated
+            }
+
+            // Deactivates a feature and returns whether it was active. Requires the Admin role.
+            pub fn deactivate(&mut self, feature: Feature) -> bool {
+                self.assert_role(Role::Admin);
+                let deactivated = self.active_features.remove(&feature);
+                if deactivated {
+                    log_scts777_event("feature_deactivated", json!({ "feature": feature }));
+                }
+                deactivated
+            }
+
+            pub fn is_feature_active(&self, feature: Feature) -> bool {
+                self.active_features.contains(&feature)
+            }
+
+            // Returns every known feature with whether it is active
+            pub fn list_features(&self) -> HashMap<Feature, bool> {
+                Feature::ALL
+                    .iter()
+                    .map(|feature| (*feature, self.active_features.contains(feature)))
+                    .collect()
+            }
+
+            fn assert_feature_active(&self, feature: Feature) {
+                if !self.active_features.contains(&feature) {
+                    env::panic(format!("Feature {:?} is not active", feature).as_bytes());
+                }
+            }
+        }
+    };
+}
+
+// Groups of state-changing functions that can be paused independently
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[serde(crate = "near_sdk::serde", rename_all = "snake_case")]
+pub enum PausableFunction {
+    Transfer,
+    Approve,
+    Mint,
+    Burn,
+    Fractionalize,
+    ChildDeployment,
+}
+
+impl PausableFunction {
+    const ALL: [PausableFunction; 6] = [
+        PausableFunction::Transfer,
+        PausableFunction::Approve,
+        PausableFunction::Mint,
+        PausableFunction::Burn,
+        PausableFunction::Fractionalize,
+        PausableFunction::ChildDeployment,
+    ];
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Clone)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PauseInfo {
+    pub reason: String,
+    pub paused_at: U64, // block timestamp in nanoseconds
+}
+
+// Implements pausing for a contract with a `paused_functions` field and access control. Revoking approvals,
+// decreasing allowances, storage management and callbacks are never paused, so users can
+// always reduce their exposure and in-flight calls can always settle.
+macro_rules! impl_pausable {
+    ($contract: ident) => {
+        #[near_bindgen]
+        impl $contract {
+            // Pauses the given functions, or all of them if none are given. Requires the
+            // Pauser role.
+            pub fn pause(&mut self, functions: Option<Vec<PausableFunction>>, reason: String) {
+                self.assert_role(Role::Pauser);
+                let paused_at = env::block_timestamp();
+                for function in functions.unwrap_or_else(|| PausableFunction::ALL.to_vec()) {
+                    self.paused_functions.insert(
+                        function,
+                        PauseInfo {
+                            reason: reason.clone(),
+                            paused_at: U64(paused_at),
+                        },
+                    );
+                    log_scts777_event(
+                        "pause",
+                        json!({ "function": function, "reason": reason, "paused_at": U64(paused_at) }),
+                    );
+                }
+            }
+
+            // Resumes the given functions, or all of them if none are given. Requires the
+            // Pauser role.
+            pub fn unpause(&mut self, functions: Option<Vec<PausableFunction>>) {
+                self.assert_role(Role::Pauser);
+                for function in functions.unwrap_or_else(|| PausableFunction::ALL.to_vec()) {
+                    if self.paused_functions.remove(&function).is_some() {
+                        log_scts777_event(
+                            "unpause",
+                            json!({ "function": function, "unpaused_at": U64(env::block_timestamp()) }),
+                        );
+                    }
+                }
+            }
+
+            pub fn is_paused(&self, function: PausableFunction) -> bool {
+                self.paused_functions.contains_key(&function)
+            }
+
+            // Returns every paused function with the reason and time it was paused
+            pub fn get_paused_functions(&self) -> HashMap<PausableFunction, PauseInfo> {
+                self.paused_functions.clone()
+            }
+
+            fn assert_not_paused(&self, function: PausableFunction) {
+                if let Some(info) = self.paused_functions.get(&function) {
+                    env::panic(format!("{:?} is paused: {}", function, info.reason).as_bytes());
+                }
+            }
+        }
+    };
+}
+
+// Implements the NEP-141 fungible token core and NEP-148 metadata for a contract
+// with `name`, `symbol`, `total_supply` and `balances` fields and the fungible token history.
+// Balances are read through `internal_balance_at` and brought up to date with
+// `internal_settle_balance` before they change, so SCTS777 can apply its time-based rate.
+macro_rules! impl_fungible_token_standard {
+    ($contract: ident) => {
+        #[near_bindgen]
+        impl $contract {
+            #[payable]
+            pub fn ft_transfer(&mut self, receiver_id: ValidAccountId, amount: U128, memo: Option<String>) {
+                assert_one_yocto();
+                self.assert_not_paused(PausableFunction::Transfer);
+                let sender_id = env::predecessor_account_id();
+                self.internal_transfer(&sender_id, receiver_id.as_ref(), amount.into(), memo);
+            }
+
+            // Transfers to a contract and calls its ft_on_transfer; any amount the receiver
+            // reports as unused is refunded in ft_resolve_transfer.
+            #[payable]
+            pub fn ft_transfer_call(
+                &mut self,
+                receiver_id: ValidAccountId,
+                amount: U128,
+                memo: Option<String>,
+                msg: String,
+            ) -> PromiseOrValue<U128> {
+                assert_one_yocto();
+                self.assert_not_paused(PausableFunction::Transfer);
+                self.assert_feature_active(Feature::Composability);
+                assert!(
+                    env::prepaid_gas() > GAS_FOR_FT_TRANSFER_CALL,
+                    "More gas is required"
+                );
+                let sender_id = env::predecessor_account_id();
+                let receiver_id: AccountId = receiver_id.into();
+                self.internal_transfer(&sender_id, &receiver_id, amount.into(), memo);
+
+                ext_fungible_token_receiver::ft_on_transfer(
+                    sender_id.clone(),
+                    amount,
+                    msg,
+                    &receiver_id,
+                    NO_DEPOSIT,
+                    env::prepaid_gas() - GAS_FOR_FT_TRANSFER_CALL,
+                )
+                .then(ext_fungible_token_resolver::ft_resolve_transfer(
+                    sender_id,
+                    receiver_id,
+                    amount,
+                    &env::current_account_id(),
+                    NO_DEPOSIT,
+                    GAS_FOR_RESOLVE_TRANSFER,
+                ))
+                .into()
+            }
+
+            pub fn ft_total_supply(&self) -> U128 {
+                self.total_supply.into()
+            }
+
+            pub fn ft_balance_of(&self, account_id: ValidAccountId) -> U128 {
+                self.internal_balance_at(account_id.as_ref(), env::block_timestamp()).into()
+            }
+
+            pub fn ft_metadata(&self) -> FungibleTokenMetadata {
+                FungibleTokenMetadata {
+                    spec: FT_METADATA_SPEC.to_string(),
+                    name: self.name.clone(),
+                    symbol: self.symbol.clone(),
+                    icon: None,
+                    reference: None,
+                    reference_hash: None,
+                    decimals: FT_DECIMALS,
+                }
+            }
+
+            // Refunds whatever the receiver did not use, limited to what it still holds.
+            // Returns the amount that stayed with the receiver.
+            #[private]
+            pub fn ft_resolve_transfer(&mut self, sender_id: AccountId, receiver_id: AccountId, amount: U128) -> U128 {
+                let amount: Balance = amount.into();
+                let unused_amount = match env::promise_result(0) {
+                    PromiseResult::NotReady => unreachable!(),
+                    PromiseResult::Successful(value) => {
+                        match near_sdk::serde_json::from_slice::<U128>(&value) {
+                            Ok(unused_amount) => std::cmp::min(amount, unused_amount.0),
+                            Err(_) => amount,
+                        }
+                    }
+                    PromiseResult::Failed => amount,
+                };
+
+                let receiver_balance = self.internal_balance_at(&receiver_id, env::block_timestamp());
+                let refund_amount = std::cmp::min(receiver_balance, unused_amount);
+                if refund_amount > 0 {
+                    self.internal_transfer(&receiver_id, &sender_id, refund_amount, Some("refund".to_string()));
+                }
+                U128(amount - refund_amount)
+            }
+
+            fn internal_transfer(&mut self, from: &AccountId, to: &AccountId, amount: Balance, memo: Option<String>) {
+                assert!(amount > 0, "Transfer amount must be positive");
+                assert_ne!(from, to, "Sender and recipient must be different");
+                self.internal_settle_balance(from);
+                self.internal_settle_balance(to);
+
+                let from_balance = self.balances.get(from).unwrap_or(0);
+                let new_from_balance = from_balance.checked_sub(amount).expect("Balance not enough");
+                let new_to_balance = self
+                    .balances
+                    .get(to)
+                    .unwrap_or(0)
+                    .checked_add(amount)
+                    .expect("Balance overflow");
+
+                if new_from_balance == 0 {
+                    self.balances.remove(from);
+                } else {
+                    self.balances.insert(from, &new_from_balance);
+                }
+                self.balances.insert(to, &new_to_balance);
+                self.internal_record_ft(Some(from), Some(to), amount, TransactionReason::Transfer);
+
+                log_ft_event(
+                    "ft_transfer",
+                    json!({
+                        "old_owner_id": from,
+                        "new_owner_id": to,
+                        "amount": U128(amount),
+                        "memo": memo,
+                    }),
+                );
+            }
+        }
+    };
+}
+
+#[cfg(feature = "self-fractalized")]
 #[near_bindgen]
 #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
 pub struct SelfFractalizedSCTS777 {
@@ -14,569 +1018,3799 @@ pub struct SelfFractalizedSCTS777 {
     balances: LookupMap<AccountId, Balance>,
     allowances: LookupMap<AccountId, LookupMap<AccountId, Balance>>,
 
-    // New fields for self-fractalization
-    child_tokens: LookupMap<String, u64>,
-    parent_token: Option<String>,
-    parent_balance: Option<Balance>,
-}
+    // New fields for self-fractalization
+    child_tokens: LookupMap<String, u64>,
+    child_token_accounts: Vector<AccountId>, // child accounts in registration order, indexed by child token ID
+    next_child_token_id: u64,
+    child_code: LazyOption<Vec<u8>>, // this contract's own Wasm, deployed to child accounts
+    pending_child_tokens: LookupSet<AccountId>, // child accounts being created, reserved until the callback
+    child_tokens_per_creator: LookupMap<AccountId, Vector<u64>>, // child token IDs in creation order
+    locked_balances: LookupMap<AccountId, Balance>, // parent tokens locked as backing, per child contract
+    parent_token: Option<String>,
+    parent_balance: Option<Balance>, // parent tokens locked in parent_token backing this supply
+    fraction_ratio: Balance, // child tokens minted per locked parent token
+
+    paused_functions: HashMap<PausableFunction, PauseInfo>,
+
+    owner_id: AccountId,
+    pending_owner_id: Option<AccountId>,
+    role_members: LookupMap<Role, UnorderedSet<AccountId>>,
+    active_features: HashSet<Feature>,
+
+    ft_history: History,
+
+    vesting_schedules: LookupMap<u64, VestingSchedule>,
+    next_vesting_id: u64,
+}
+
+#[cfg(feature = "self-fractalized")]
+impl_fungible_token_standard!(SelfFractalizedSCTS777);
+#[cfg(feature = "self-fractalized")]
+impl_pausable!(SelfFractalizedSCTS777);
+#[cfg(feature = "self-fractalized")]
+impl_access_control!(SelfFractalizedSCTS777);
+#[cfg(feature = "self-fractalized")]
+impl_feature_flags!(SelfFractalizedSCTS777);
+#[cfg(feature = "self-fractalized")]
+impl_ft_history!(SelfFractalizedSCTS777);
+
+#[cfg(feature = "self-fractalized")]
+#[near_bindgen]
+impl SelfFractalizedSCTS777 {
+    // Constructor
+    #[init]
+    pub fn new(name: String, symbol: String, total_supply: U128) -> Self {
+        let owner_id = env::predecessor_account_id();
+        let mut instance = Self::internal_new(name, symbol, owner_id.clone());
+        instance.internal_mint_initial_supply(&owner_id, total_supply.into());
+        instance
+    }
+
+    // Initializes a child token deployed by create_child_token. The caller is the parent
+    // contract, which has locked `total_supply / fraction_ratio` of the creator's parent
+    // tokens to back the supply. The creator becomes the child's owner and receives the
+    // supply, so it can redeem the backing through the parent.
+    #[init]
+    pub fn new_child(name: String, symbol: String, total_supply: U128, fraction_ratio: U128, owner_id: AccountId) -> Self {
+        assert!(fraction_ratio.0 > 0, "Ratio must be positive");
+        let mut instance = Self::internal_new(name, symbol, owner_id.clone());
+        instance.parent_token = Some(env::predecessor_account_id());
+        instance.parent_balance = Some(total_supply.0 / fraction_ratio.0);
+        instance.fraction_ratio = fraction_ratio.0;
+        instance.internal_mint_initial_supply(&owner_id, total_supply.into());
+        instance.assert_supply_backed();
+        instance
+    }
+
+    // Builds state with no supply
+    fn internal_new(name: String, symbol: String, owner_id: AccountId) -> Self {
+        Self {
+            name,
+            symbol,
+            total_supply: 0,
+            balances: LookupMap::new(StorageKey::Balances),
+            allowances: LookupMap::new(StorageKey::Allowances),
+            child_tokens: LookupMap::new(StorageKey::ChildTokens),
+            child_token_accounts: Vector::new(StorageKey::ChildTokenAccounts),
+            next_child_token_id: 0,
+            child_code: LazyOption::new(StorageKey::ChildCode, None),
+            pending_child_tokens: LookupSet::new(StorageKey::PendingChildTokens),
+            child_tokens_per_creator: LookupMap::new(StorageKey::ChildTokensPerCreator),
+            locked_balances: LookupMap::new(StorageKey::LockedBalances),
+            parent_token: None,
+            parent_balance: None,
+            fraction_ratio: 1,
+            paused_functions: HashMap::new(),
+            owner_id,
+            pending_owner_id: None,
+            role_members: LookupMap::new(StorageKey::RoleMembers),
+            active_features: Feature::ALL.iter().copied().collect(),
+            ft_history: History::new(StorageKey::FtHistory, MAX_FT_HISTORY_LEN),
+            vesting_schedules: LookupMap::new(StorageKey::VestingSchedules),
+            next_vesting_id: 0,
+        }
+    }
+
+    // Mints the initial supply to `owner_id`
+    fn internal_mint_initial_supply(&mut self, owner_id: &AccountId, amount: Balance) {
+        self.total_supply = amount;
+        self.balances.insert(owner_id, &amount);
+        self.internal_record_ft(None, Some(owner_id), amount, TransactionReason::Mint);
+        log_ft_event("ft_mint", json!({ "owner_id": owner_id, "amount": U128(amount) }));
+    }
+
+    // Standard functions
+
+    // Returns the balance of a specific token holder.
+    pub fn balance_of(&self, account_id: ValidAccountId) -> U128 {
+        self.balances.get(account_id.as_ref()).unwrap_or(0).into()
+    }
+
+    // Transfers tokens from the caller to another account.
+    #[payable]
+    pub fn transfer(&mut self, to: ValidAccountId, amount: U128) -> bool {
+        assert_one_yocto();
+        self.assert_not_paused(PausableFunction::Transfer);
+        let from = env::predecessor_account_id();
+        self.internal_transfer(&from, to.as_ref(), amount.into(), None);
+        true
+    }
+
+    // Approves a third-party spender to transfer up to `amount` tokens on behalf of the caller,
+    // replacing any previous allowance. The attached deposit pays for a new allowance's
+    // storage and the rest of it is refunded.
+    #[payable]
+    pub fn approve(&mut self, spender: ValidAccountId, amount: U128) -> bool {
+        assert!(env::attached_deposit() >= 1, "Requires attached deposit of at least 1 yoctoNEAR");
+        self.assert_not_paused(PausableFunction::Approve);
+        let owner = env::predecessor_account_id();
+        self.internal_set_allowance(&owner, spender.as_ref(), amount.into());
+        true
+    }
+
+    // Returns how many tokens `spender` may still transfer on behalf of `owner`.
+    pub fn allowance(&self, owner: ValidAccountId, spender: ValidAccountId) -> U128 {
+        self.internal_allowance(owner.as_ref(), spender.as_ref()).into()
+    }
+
+    // Transfers tokens from one account to another on behalf of the token holder,
+    // consuming the caller's allowance.
+    #[payable]
+    pub fn transfer_from(&mut self, from: ValidAccountId, to: ValidAccountId, amount: U128) -> bool {
+        assert_one_yocto();
+        self.assert_not_paused(PausableFunction::Transfer);
+        let spender = env::predecessor_account_id();
+        let amount: Balance = amount.into();
+        let new_allowance = self
+            .internal_allowance(from.as_ref(), &spender)
+            .checked_sub(amount)
+            .expect("Allowance not enough");
+
+        self.internal_transfer(from.as_ref(), to.as_ref(), amount, None);
+        self.internal_set_allowance(from.as_ref(), &spender, new_allowance);
+        true
+    }
+
+    // Raises the caller's allowance for `spender` by `added_amount`. Like approve, a new
+    // allowance needs a deposit for its storage.
+    #[payable]
+    pub fn increase_allowance(&mut self, spender: ValidAccountId, added_amount: U128) -> bool {
+        self.assert_not_paused(PausableFunction::Approve);
+        let owner = env::predecessor_account_id();
+        let new_allowance = self
+            .internal_allowance(&owner, spender.as_ref())
+            .checked_add(added_amount.into())
+            .expect("Allowance overflow");
+        self.internal_set_allowance(&owner, spender.as_ref(), new_allowance);
+        true
+    }
+
+    // Lowers the caller's allowance for `spender` by `subtracted_amount`.
+    pub fn decrease_allowance(&mut self, spender: ValidAccountId, subtracted_amount: U128) -> bool {
+        let owner = env::predecessor_account_id();
+        let new_allowance = self
+            .internal_allowance(&owner, spender.as_ref())
+            .checked_sub(subtracted_amount.into())
+            .expect("Decreased allowance below zero");
+        self.internal_set_allowance(&owner, spender.as_ref(), new_allowance);
+        true
+    }
+
+    fn internal_allowance(&self, owner: &AccountId, spender: &AccountId) -> Balance {
+        self.allowances
+            .get(owner)
+            .and_then(|owner_allowances| owner_allowances.get(spender))
+            .unwrap_or(0)
+    }
+
+    // Sets an allowance, paying for the bytes it adds from the attached deposit and refunding
+    // the rest to the caller. Bytes freed by removing an allowance are refunded to the owner,
+    // who paid for them.
+    fn internal_set_allowance(&mut self, owner: &AccountId, spender: &AccountId, amount: Balance) {
+        let initial_storage_usage = env::storage_usage();
+        let mut owner_allowances = self
+            .allowances
+            .get(owner)
+            .unwrap_or_else(|| LookupMap::new(StorageKey::AllowancesPerOwner {
+                    account_hash: env::sha256(owner.as_bytes()),
+                }));
+        if amount == 0 {
+            owner_allowances.remove(spender);
+        } else {
+            owner_allowances.insert(spender, &amount);
+        }
+        self.allowances.insert(owner, &owner_allowances);
+
+        let current_storage_usage = env::storage_usage();
+        let deposit = env::attached_deposit();
+        let storage_cost = Balance::from(current_storage_usage.saturating_sub(initial_storage_usage)) * env::storage_byte_cost();
+        assert!(
+            deposit >= storage_cost,
+            "Must attach {} yoctoNEAR to cover the allowance storage",
+            storage_cost
+        );
+        // A single yoctoNEAR only confirms the call and is kept
+        if deposit - storage_cost > 1 {
+            Promise::new(env::predecessor_account_id()).transfer(deposit - storage_cost);
+        }
+        if current_storage_usage < initial_storage_usage {
+            let freed_cost = Balance::from(initial_storage_usage - current_storage_usage) * env::storage_byte_cost();
+            Promise::new(owner.clone()).transfer(freed_cost);
+        }
+
+        log_scts777_event(
+            "approval",
+            json!({ "owner_id": owner, "spender_id": spender, "amount": U128(amount) }),
+        );
+    }
+
+    // Functions for self-fractalization
+
+    // Stores this contract's own Wasm, passed as the raw call input, so that
+    // create_child_token can deploy it. Requires the Admin role.
+    pub fn set_child_code(&mut self) {
+        self.assert_role(Role::Admin);
+        let code = env::input().expect("Expected the contract code as input");
+        self.child_code.set(&code);
+    }
+
+    // Deploys a new SelfFractalizedSCTS777 to the `<prefix>.<current account>` subaccount and
+    // initializes it once with the given name, symbol and supply, which is minted to the
+    // caller. Each of the caller's tokens locked here backs `fraction_ratio` child tokens
+    // (one by default), so the supply must be a multiple of the ratio.
+    // The attached deposit funds the child account. The child is only registered once
+    // deployment and initialization succeed; otherwise the deposit and backing are returned.
+    // The child account stays reserved until then.
+    #[payable]
+    pub fn create_child_token(
+        &mut self,
+        prefix: String,
+        name: String,
+        symbol: String,
+        total_supply: U128,
+        fraction_ratio: Option<U128>,
+    ) -> Promise {
+        self.assert_not_paused(PausableFunction::ChildDeployment);
+        self.assert_feature_active(Feature::SelfFractalization);
+        assert!(!prefix.contains('.'), "The prefix cannot contain '.'");
+        let child_account_id = format!("{}.{}", prefix, env::current_account_id());
+        assert!(
+            env::is_valid_account_id(child_account_id.as_bytes()),
+            "Invalid child account ID"
+        );
+        assert!(
+            !self.child_tokens.contains_key(&child_account_id),
+            "Child token already exists"
+        );
+        assert!(
+            !self.pending_child_tokens.contains(&child_account_id),
+            "Child token is already being created"
+        );
+        let fraction_ratio = fraction_ratio.unwrap_or(U128(1));
+        assert!(fraction_ratio.0 > 0, "Ratio must be positive");
+        assert_eq!(
+            total_supply.0 % fraction_ratio.0,
+            0,
+            "The supply must be a multiple of the ratio"
+        );
+
+        let code = self.child_code.get().expect("The child contract code has not been set");
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= code.len() as Balance * env::storage_byte_cost() + CHILD_STATE_DEPOSIT,
+            "Not enough deposit to deploy the child token"
+        );
+        let backing = U128(total_supply.0 / fraction_ratio.0);
+        let creator_id = env::predecessor_account_id();
+        self.internal_lock(&creator_id, &child_account_id, backing.0);
+        self.pending_child_tokens.insert(&child_account_id);
+
+        Promise::new(child_account_id.clone())
+            .create_account()
+            .transfer(deposit)
+            .deploy_contract(code)
+            .function_call(
+                b"new_child".to_vec(),
+                json!({
+                    "name": name,
+                    "symbol": symbol,
+                    "total_supply": total_supply,
+                    "fraction_ratio": fraction_ratio,
+                    "owner_id": creator_id,
+                })
+                .to_string()
+                .into_bytes(),
+                NO_DEPOSIT,
+                GAS_FOR_CHILD_INIT,
+            )
+            .then(ext_self_fractalized_scts777::on_child_token_created(
+                child_account_id,
+                creator_id,
+                backing,
+                U128(deposit),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_CHILD_CALLBACK,
+            ))
+    }
+
+    // Registers the child token if it was deployed and initialized, otherwise returns the
+    // `backing` locked for it and the deposit to the creator. A failed deployment returns
+    // the deposit to this contract first.
+    #[private]
+    pub fn on_child_token_created(
+        &mut self,
+        child_account_id: AccountId,
+        creator_id: AccountId,
+        backing: U128,
+        deposit: U128,
+    ) -> bool {
+        self.pending_child_tokens.remove(&child_account_id);
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                let child_token_id = self.next_child_token_id;
+                self.next_child_token_id += 1;
+                self.child_tokens.insert(&child_account_id, &child_token_id);
+                self.child_token_accounts.push(&child_account_id);
+                let mut created_tokens = self.child_tokens_per_creator.get(&creator_id).unwrap_or_else(|| {
+                    Vector::new(StorageKey::ChildTokensPerCreatorInner {
+                        account_hash: env::sha256(creator_id.as_bytes()),
+                    })
+                });
+                created_tokens.push(&child_token_id);
+                self.child_tokens_per_creator.insert(&creator_id, &created_tokens);
+                log_scts777_event(
+                    "child_token_create",
+                    json!({
+                        "child_token_id": child_token_id,
+                        "child_account_id": child_account_id,
+                        "creator_id": creator_id,
+                        "backing": backing,
+                    }),
+                );
+                true
+            }
+            _ => {
+                self.internal_unlock(&child_account_id, &creator_id, backing.into());
+                Promise::new(creator_id.clone()).transfer(deposit.0);
+                log_scts777_event(
+                    "child_token_create_failure",
+                    json!({
+                        "child_account_id": child_account_id,
+                        "creator_id": creator_id,
+                        "refunded_backing": backing,
+                        "refunded_deposit": deposit,
+                    }),
+                );
+                false
+            }
+        }
+    }
+
+    // Parent-child supply linkage
+    // A child's supply is backed by parent tokens locked in the parent contract, each worth
+    // fraction_ratio child tokens (chosen when create_child_token creates the child): locking
+    // parent tokens mints child tokens, and burning child tokens releases them.
+    // A linked child's total supply never exceeds parent_balance * fraction_ratio.
+
+    // Links this contract to the parent token contract that will back its supply.
+    // Only possible while nothing has been minted, since every token must be backed.
+    pub fn set_parent_token(&mut self, parent_token: String) {
+        self.assert_role(Role::Admin);
+        assert!(
+            self.parent_token.is_none(),
+            "Parent token already set"
+        );
+        assert!(
+            !self.child_tokens.contains_key(&parent_token),
+            "Cannot set parent token to a child token"
+        );
+        assert_eq!(self.total_supply, 0, "Existing supply is not backed by the parent token");
+
+        self.parent_token = Some(parent_token);
+        self.parent_balance = Some(0);
+    }
+
+    // Unlinks this contract from its parent once all backed tokens have been burned.
+    pub fn remove_parent_token(&mut self) {
+        self.assert_role(Role::Admin);
+        assert!(
+            self.parent_token.is_some(),
+            "Parent token not set"
+        );
+        assert!(
+            self.total_supply == 0 && self.parent_balance == Some(0),
+            "Child tokens are still backed by locked parent tokens"
+        );
+
+        self.parent_token = None;
+        self.parent_balance = None;
+    }
+
+    // Parent side: locks `amount` of the caller's tokens as backing for a registered child
+    // and mints the same amount of child tokens to the caller. The lock is undone if the
+    // child fails to mint.
+    pub fn lock_for_child(&mut self, child_account_id: AccountId, amount: U128) -> Promise {
+        self.fractionalize(child_account_id, amount, U128(1))
+    }
+
+    // Parent side: escrows `amount` of the caller's tokens for a registered child and mints
+    // exactly `amount * ratio` child tokens (fractions) to the caller. A child has a single
+    // ratio, fixed by its first backing and only changeable once its supply is fully redeemed,
+    // so a call with a different ratio fails in the child and the escrow is returned.
+    pub fn fractionalize(&mut self, child_account_id: AccountId, amount: U128, ratio: U128) -> Promise {
+        self.assert_not_paused(PausableFunction::Fractionalize);
+        self.assert_feature_active(Feature::SelfFractalization);
+        assert!(
+            self.child_tokens.contains_key(&child_account_id),
+            "Child token does not exist"
+        );
+        assert!(ratio.0 > 0, "Ratio must be positive");
+        amount.0.checked_mul(ratio.0).expect("Fraction amount overflow");
+        let owner_id = env::predecessor_account_id();
+        self.internal_lock(&owner_id, &child_account_id, amount.0);
+
+        ext_child_token::mint_backed(owner_id.clone(), amount, ratio, &child_account_id, NO_DEPOSIT, GAS_FOR_BACKING_CALL)
+            .then(ext_self_fractalized_scts777::on_child_minted(
+                child_account_id,
+                owner_id,
+                amount,
+                ratio,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_CHILD_CALLBACK,
+            ))
+    }
+
+    #[private]
+    pub fn on_child_minted(&mut self, child_account_id: AccountId, owner_id: AccountId, amount: U128, ratio: U128) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                log_scts777_event(
+                    "fractionalize",
+                    json!({
+                        "owner_id": owner_id,
+                        "child_account_id": child_account_id,
+                        "amount": amount,
+                        "ratio": ratio,
+                        "fractions": U128(amount.0 * ratio.0),
+                    }),
+                );
+                true
+            }
+            _ => {
+                self.internal_unlock(&child_account_id, &owner_id, amount.0);
+                false
+            }
+        }
+    }
+
+    // Parent side: burns up to `amount` of the caller's fractions in a registered child and
+    // releases `amount / ratio` escrowed parent tokens to the caller. The amount is rounded
+    // down to a whole number of parent tokens; the remaining fractions stay with the caller.
+    // Returns the number of parent tokens released.
+    pub fn redeem(&mut self, child_account_id: AccountId, amount: U128) -> Promise {
+        self.assert_not_paused(PausableFunction::Burn);
+        assert!(
+            self.child_tokens.contains_key(&child_account_id),
+            "Child token does not exist"
+        );
+        assert!(amount.0 > 0, "Redeem amount must be positive");
+        let owner_id = env::predecessor_account_id();
+
+        ext_child_token::burn_for_redeem(owner_id.clone(), amount, &child_account_id, NO_DEPOSIT, GAS_FOR_BACKING_CALL)
+            .then(ext_self_fractalized_scts777::on_fractions_redeemed(
+                child_account_id,
+                owner_id,
+                amount,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_CHILD_CALLBACK,
+            ))
+    }
+
+    #[private]
+    pub fn on_fractions_redeemed(&mut self, child_account_id: AccountId, owner_id: AccountId, amount: U128) -> U128 {
+        let released = match env::promise_result(0) {
+            PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<U128>(&value)
+                .expect("Invalid redeem result")
+                .0,
+            _ => 0,
+        };
+        if released > 0 {
+            self.internal_unlock(&child_account_id, &owner_id, released);
+            log_scts777_event(
+                "redeem",
+                json!({
+                    "owner_id": owner_id,
+                    "child_account_id": child_account_id,
+                    "amount": amount,
+                    "released": U128(released),
+                }),
+            );
+        }
+        U128(released)
+    }
+
+    // Parent side: called by a registered child after it burned `amount` of its tokens,
+    // releasing the same amount of locked backing to `receiver_id`.
+    pub fn release_from_child(&mut self, receiver_id: AccountId, amount: U128) {
+        self.assert_not_paused(PausableFunction::Burn);
+        let child_account_id = env::predecessor_account_id();
+        assert!(
+            self.child_tokens.contains_key(&child_account_id),
+            "Only a child token can release backing"
+        );
+        self.internal_unlock(&child_account_id, &receiver_id, amount.0);
+    }
+
+    pub fn get_locked_balance(&self, child_account_id: AccountId) -> U128 {
+        U128(self.locked_balances.get(&child_account_id).unwrap_or(0))
+    }
+
+    // Child side: mints `amount * ratio` tokens backed by `amount` parent tokens the parent
+    // contract has just locked. The ratio can only change while nothing is backed.
+    pub fn mint_backed(&mut self, receiver_id: AccountId, amount: U128, ratio: U128) {
+        self.assert_not_paused(PausableFunction::Mint);
+        assert_eq!(
+            Some(env::predecessor_account_id()),
+            self.parent_token,
+            "Only the parent token can mint backed tokens"
+        );
+        let (amount, ratio) = (amount.0, ratio.0);
+        assert!(ratio > 0, "Ratio must be positive");
+        if self.parent_balance.unwrap_or(0) == 0 {
+            self.fraction_ratio = ratio;
+        } else {
+            assert_eq!(ratio, self.fraction_ratio, "Ratio does not match the existing fractions");
+        }
+        let minted = amount.checked_mul(ratio).expect("Fraction amount overflow");
+        self.parent_balance = Some(self.parent_balance.unwrap_or(0).checked_add(amount).expect("Backing overflow"));
+        self.total_supply = self.total_supply.checked_add(minted).expect("Total supply overflow");
+        let balance = self.balances.get(&receiver_id).unwrap_or(0);
+        self.balances.insert(&receiver_id, &balance.checked_add(minted).expect("Balance overflow"));
+        self.assert_supply_backed();
+        self.internal_record_ft(None, Some(&receiver_id), minted, TransactionReason::Mint);
+
+        log_ft_event("ft_mint", json!({ "owner_id": receiver_id, "amount": U128(minted) }));
+    }
+
+    // Child side: burns up to `amount` of the caller's tokens and asks the parent to release
+    // the backing parent tokens to the caller, rounding down as in redeem.
+    // The burn is undone if the release fails.
+    pub fn burn_backed(&mut self, amount: U128) -> Promise {
+        self.assert_not_paused(PausableFunction::Burn);
+        let parent_token = self.parent_token.clone().expect("Parent token not set");
+        let owner_id = env::predecessor_account_id();
+        let released = self.internal_burn_backed(&owner_id, amount.0);
+
+        ext_parent_token::release_from_child(owner_id.clone(), U128(released), &parent_token, NO_DEPOSIT, GAS_FOR_BACKING_CALL)
+            .then(ext_self_fractalized_scts777::on_parent_released(
+                owner_id,
+                U128(released),
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_CHILD_CALLBACK,
+            ))
+    }
+
+    // Child side: burns fractions on behalf of `owner_id` for the parent's redeem, which
+    // releases the returned number of parent tokens once this call succeeds.
+    pub fn burn_for_redeem(&mut self, owner_id: AccountId, amount: U128) -> U128 {
+        self.assert_not_paused(PausableFunction::Burn);
+        assert_eq!(
+            Some(env::predecessor_account_id()),
+            self.parent_token,
+            "Only the parent token can redeem fractions"
+        );
+        U128(self.internal_burn_backed(&owner_id, amount.0))
+    }
+
+    #[private]
+    pub fn on_parent_released(&mut self, owner_id: AccountId, amount: U128) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => true,
+            _ => {
+                // The backing is still locked in the parent, so the burned tokens are restored
+                let restored = amount.0 * self.fraction_ratio;
+                self.parent_balance = Some(self.parent_balance.unwrap_or(0) + amount.0);
+                self.total_supply += restored;
+                let balance = self.balances.get(&owner_id).unwrap_or(0);
+                self.balances.insert(&owner_id, &(balance + restored));
+                self.internal_record_ft(None, Some(&owner_id), restored, TransactionReason::Mint);
+                log_ft_event("ft_mint", json!({ "owner_id": owner_id, "amount": U128(restored), "memo": "release failed" }));
+                false
+            }
+        }
+    }
+
+    // Burns the largest multiple of fraction_ratio not above `amount` and returns the number
+    // of parent tokens it was backed by
+    fn internal_burn_backed(&mut self, owner_id: &AccountId, amount: Balance) -> Balance {
+        let released = amount / self.fraction_ratio;
+        assert!(released > 0, "Amount is less than one parent token worth of fractions");
+        let burned = released * self.fraction_ratio;
+        let balance = self.balances.get(owner_id).unwrap_or(0);
+        let new_balance = balance.checked_sub(burned).expect("Balance not enough");
+        if new_balance == 0 {
+            self.balances.remove(owner_id);
+        } else {
+            self.balances.insert(owner_id, &new_balance);
+        }
+        self.total_supply -= burned;
+        self.parent_balance = Some(
+            self.parent_balance
+                .unwrap_or(0)
+                .checked_sub(released)
+                .expect("Burn exceeds locked backing"),
+        );
+        self.assert_supply_backed();
+        self.internal_record_ft(Some(owner_id), None, burned, TransactionReason::Burn);
+
+        log_ft_event("ft_burn", json!({ "owner_id": owner_id, "amount": U128(burned) }));
+        released
+    }
+
+    // Moves `amount` of the owner's tokens into the backing locked for a child
+    fn internal_lock(&mut self, owner_id: &AccountId, child_account_id: &AccountId, amount: Balance) {
+        assert!(amount > 0, "Lock amount must be positive");
+        let balance = self.balances.get(owner_id).unwrap_or(0);
+        let new_balance = balance.checked_sub(amount).expect("Balance not enough");
+        if new_balance == 0 {
+            self.balances.remove(owner_id);
+        } else {
+            self.balances.insert(owner_id, &new_balance);
+        }
+        let locked = self.locked_balances.get(child_account_id).unwrap_or(0);
+        self.locked_balances
+            .insert(child_account_id, &locked.checked_add(amount).expect("Locked balance overflow"));
+        self.internal_record_ft(Some(owner_id), Some(child_account_id), amount, TransactionReason::Lock);
+
+        log_scts777_event(
+            "lock",
+            json!({ "owner_id": owner_id, "child_account_id": child_account_id, "amount": U128(amount) }),
+        );
+    }
+
+    // Releases `amount` of the backing locked for a child to `receiver_id`
+    fn internal_unlock(&mut self, child_account_id: &AccountId, receiver_id: &AccountId, amount: Balance) {
+        let locked = self.locked_balances.get(child_account_id).unwrap_or(0);
+        let new_locked = locked.checked_sub(amount).expect("Not enough locked backing");
+        if new_locked == 0 {
+            self.locked_balances.remove(child_account_id);
+        } else {
+            self.locked_balances.insert(child_account_id, &new_locked);
+        }
+        let balance = self.balances.get(receiver_id).unwrap_or(0);
+        self.balances
+            .insert(receiver_id, &balance.checked_add(amount).expect("Balance overflow"));
+        self.internal_record_ft(Some(child_account_id), Some(receiver_id), amount, TransactionReason::Release);
+
+        log_scts777_event(
+            "release",
+            json!({ "child_account_id": child_account_id, "receiver_id": receiver_id, "amount": U128(amount) }),
+        );
+    }
+
+    // Balances of SelfFractalizedSCTS777 do not change over time
+    fn internal_balance_at(&self, account_id: &AccountId, _timestamp: u64) -> Balance {
+        self.balances.get(account_id).unwrap_or(0)
+    }
+
+    fn internal_settle_balance(&mut self, _account_id: &AccountId) {}
+
+    fn assert_supply_backed(&self) {
+        if self.parent_token.is_some() {
+            assert!(
+                self.total_supply <= self.parent_balance.unwrap_or(0) * self.fraction_ratio,
+                "Child supply exceeds the locked parent backing"
+            );
+        }
+    }
+    
+    // Lists the child tokens of `parent` in the order they were created. Every child deployed
+    // here has this contract as its parent, and a child's own children are registered in the
+    // child contract, so any other parent has no children listed here.
+    pub fn get_child_tokens(&self, parent: ValidAccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<ChildTokenView> {
+        if parent.as_ref() != &env::current_account_id() {
+            return vec![];
+        }
+        let start = u128::from(from_index.unwrap_or(U128(0)));
+        paginate(&self.child_token_accounts, from_index, limit)
+            .into_iter()
+            .enumerate()
+            .map(|(offset, account_id)| self.internal_child_token_view((start + offset as u128) as u64, account_id))
+            .collect()
+    }
+
+    // Lists the child tokens created by `account_id`, who received their initial supply,
+    // in the order they were created
+    pub fn get_tokens_for_owner(&self, account_id: ValidAccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<ChildTokenView> {
+        self.child_tokens_per_creator
+            .get(account_id.as_ref())
+            .map_or_else(Vec::new, |created_tokens| paginate(&created_tokens, from_index, limit))
+            .into_iter()
+            .map(|child_token_id| {
+                let account_id = self.child_token_accounts.get(child_token_id).unwrap();
+                self.internal_child_token_view(child_token_id, account_id)
+            })
+            .collect()
+    }
+
+    fn internal_child_token_view(&self, child_token_id: u64, account_id: AccountId) -> ChildTokenView {
+        ChildTokenView {
+            child_token_id,
+            locked_balance: U128(self.locked_balances.get(&account_id).unwrap_or(0)),
+            account_id,
+        }
+    }
+
+    pub fn get_child_token_count(&self) -> u64 {
+        self.child_token_accounts.len()
+    }
+}
+
+#[cfg(feature = "self-fractalized")]
+#[near_bindgen]
+impl SelfFractalizedSCTS777 {
+    // Vesting
+    // A grantor, typically the creator allocating a child token's supply, escrows tokens in
+    // a vesting schedule. The beneficiary claims them as they vest. Like child token backing,
+    // escrowed tokens belong to no account; the history records them as locked and released.
+
+    // Escrows `amount` of the caller's tokens for `beneficiary_id` and returns the schedule ID.
+    // The attached deposit pays for the schedule's storage and the rest of it is refunded.
+    // The storage deposit is returned once the schedule is fully claimed, or on revoke if
+    // nothing vested is left to claim.
+    #[payable]
+    pub fn create_vesting_schedule(
+        &mut self,
+        beneficiary_id: ValidAccountId,
+        amount: U128,
+        start: U64,
+        cliff: U64,
+        duration: U64,
+        revocable: bool,
+    ) -> u64 {
+        self.assert_not_paused(PausableFunction::Transfer);
+        assert!(amount.0 > 0, "Vesting amount must be positive");
+        assert!(duration.0 > 0, "Vesting duration must be positive");
+        assert!(cliff.0 <= duration.0, "The cliff cannot be longer than the vesting duration");
+        let grantor_id = env::predecessor_account_id();
+        let balance = self.balances.get(&grantor_id).unwrap_or(0);
+        let new_balance = balance.checked_sub(amount.0).expect("Balance not enough");
+        if new_balance == 0 {
+            self.balances.remove(&grantor_id);
+        } else {
+            self.balances.insert(&grantor_id, &new_balance);
+        }
+        self.internal_record_ft(
+            Some(&grantor_id),
+            Some(&env::current_account_id()),
+            amount.0,
+            TransactionReason::Lock,
+        );
+
+        let schedule_id = self.next_vesting_id;
+        self.next_vesting_id += 1;
+        let mut schedule = VestingSchedule {
+            grantor_id,
+            beneficiary_id: beneficiary_id.into(),
+            total: amount,
+            claimed: U128(0),
+            start,
+            cliff,
+            duration,
+            revocable,
+            revoked: false,
+            storage_deposit: U128(0),
+        };
+        // The deposit has a fixed size, so the schedule can be measured before it is final
+        let initial_storage_usage = env::storage_usage();
+        self.vesting_schedules.insert(&schedule_id, &schedule);
+        let storage_deposit = Balance::from(env::storage_usage() - initial_storage_usage) * env::storage_byte_cost();
+        let deposit = env::attached_deposit();
+        assert!(
+            deposit >= storage_deposit,
+            "Must attach {} yoctoNEAR to cover the vesting schedule storage",
+            storage_deposit
+        );
+        if deposit > storage_deposit {
+            Promise::new(schedule.grantor_id.clone()).transfer(deposit - storage_deposit);
+        }
+        schedule.storage_deposit = U128(storage_deposit);
+        self.vesting_schedules.insert(&schedule_id, &schedule);
+        log_scts777_event("vesting_create", json!({ "schedule_id": schedule_id, "schedule": schedule }));
+        schedule_id
+    }
+
+    // Transfers everything that has vested and not been claimed to the beneficiary and
+    // returns the claimed amount
+    pub fn claim(&mut self, schedule_id: u64) -> U128 {
+        self.assert_not_paused(PausableFunction::Transfer);
+        let mut schedule = self.vesting_schedules.get(&schedule_id).expect("Vesting schedule not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            schedule.beneficiary_id,
+            "Only the beneficiary can claim"
+        );
+        let claimable = schedule.vested_at(env::block_timestamp()) - schedule.claimed.0;
+        assert!(claimable > 0, "Nothing to claim");
+        schedule.claimed = U128(schedule.claimed.0 + claimable);
+        self.internal_pay_vesting(schedule_id, &schedule, claimable);
+        U128(claimable)
+    }
+
+    // Ends a revocable schedule. What has vested stays claimable by the beneficiary and the
+    // rest returns to the grantor. Returns the amount returned.
+    pub fn revoke_vesting(&mut self, schedule_id: u64) -> U128 {
+        let mut schedule = self.vesting_schedules.get(&schedule_id).expect("Vesting schedule not found");
+        assert_eq!(
+            env::predecessor_account_id(),
+            schedule.grantor_id,
+            "Only the grantor can revoke a vesting schedule"
+        );
+        assert!(schedule.revocable, "Vesting schedule is not revocable");
+        assert!(!schedule.revoked, "Vesting schedule is already revoked");
+        let vested = schedule.vested_at(env::block_timestamp());
+        let unvested = schedule.total.0 - vested;
+        schedule.total = U128(vested);
+        schedule.revoked = true;
+
+        let balance = self.balances.get(&schedule.grantor_id).unwrap_or(0);
+        if unvested > 0 {
+            self.balances
+                .insert(&schedule.grantor_id, &balance.checked_add(unvested).expect("Balance overflow"));
+            self.internal_record_ft(
+                Some(&env::current_account_id()),
+                Some(&schedule.grantor_id),
+                unvested,
+                TransactionReason::Release,
+            );
+        }
+        log_scts777_event(
+            "vesting_revoke",
+            json!({ "schedule_id": schedule_id, "returned": U128(unvested) }),
+        );
+        self.internal_save_vesting(schedule_id, &schedule);
+        U128(unvested)
+    }
+
+    pub fn get_vesting_schedule(&self, schedule_id: u64) -> Option<VestingSchedule> {
+        self.vesting_schedules.get(&schedule_id)
+    }
+
+    // Returns how much of the schedule has vested at `timestamp`, or now if not given
+    pub fn get_vested_amount(&self, schedule_id: u64, timestamp: Option<U64>) -> U128 {
+        let schedule = self.vesting_schedules.get(&schedule_id).expect("Vesting schedule not found");
+        let timestamp = timestamp.map_or_else(env::block_timestamp, |timestamp| timestamp.0);
+        U128(schedule.vested_at(timestamp))
+    }
+
+    // Returns how much the beneficiary could claim at `timestamp`, or now if not given
+    pub fn get_claimable_amount(&self, schedule_id: u64, timestamp: Option<U64>) -> U128 {
+        let schedule = self.vesting_schedules.get(&schedule_id).expect("Vesting schedule not found");
+        let timestamp = timestamp.map_or_else(env::block_timestamp, |timestamp| timestamp.0);
+        U128(schedule.vested_at(timestamp).saturating_sub(schedule.claimed.0))
+    }
+
+    fn internal_pay_vesting(&mut self, schedule_id: u64, schedule: &VestingSchedule, amount: Balance) {
+        let balance = self.balances.get(&schedule.beneficiary_id).unwrap_or(0);
+        self.balances
+            .insert(&schedule.beneficiary_id, &balance.checked_add(amount).expect("Balance overflow"));
+        self.internal_record_ft(
+            Some(&env::current_account_id()),
+            Some(&schedule.beneficiary_id),
+            amount,
+            TransactionReason::Release,
+        );
+        log_scts777_event(
+            "vesting_claim",
+            json!({ "schedule_id": schedule_id, "beneficiary_id": schedule.beneficiary_id, "amount": U128(amount) }),
+        );
+        self.internal_save_vesting(schedule_id, schedule);
+    }
+
+    // Stores the schedule, or removes it and returns its storage deposit to the grantor once
+    // everything it will ever vest has been claimed
+    fn internal_save_vesting(&mut self, schedule_id: u64, schedule: &VestingSchedule) {
+        if schedule.claimed.0 < schedule.total.0 {
+            self.vesting_schedules.insert(&schedule_id, schedule);
+            return;
+        }
+        self.vesting_schedules.remove(&schedule_id);
+        if schedule.storage_deposit.0 > 0 {
+            Promise::new(schedule.grantor_id.clone()).transfer(schedule.storage_deposit.0);
+        }
+    }
+}
+
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
+    pub struct SCTS777 {
+        // Standard fields
+        name: String,
+        symbol: String,
+        total_supply: Balance,
+        balances: LookupMap<AccountId, Balance>,
+        allowances: LookupMap<AccountId, LookupMap<AccountId, Balance>>,
+    
+        // New fields for non-fungible tokens and granchild tokens
+        next_token_id: u64,
+        token_owners: LookupMap<u64, AccountId>,
+        token_balances: LookupMap<AccountId, LookupMap<u64, Balance>>,
+        token_approvals: LookupMap<u64, HashMap<AccountId, u64>>,
+        next_approval_id: LookupMap<u64, u64>,
+        grandchild_tokens: LookupMap<u64, UnorderedSet<u64>>, // parent token -> grandchild tokens
+        grandchild_token_ids: UnorderedSet<u64>,
+        tokens_per_owner: LookupMap<AccountId, UnorderedSet<u64>>,
+
+        // Storage staking (NEP-145)
+        storage_balances: LookupMap<AccountId, AccountStorage>,
+        account_storage_usage: StorageUsage,
+        token_storage_payers: LookupMap<u64, TokenStorage>, // returned to the payer when the token is burned
+
+        paused_functions: HashMap<PausableFunction, PauseInfo>,
+
+        owner_id: AccountId,
+        pending_owner_id: Option<AccountId>,
+        role_members: LookupMap<Role, UnorderedSet<AccountId>>,
+        active_features: HashSet<Feature>,
+
+        token_metadata: LookupMap<u64, StoredTokenMetadata>,
+
+        // Transfer history, recorded while the History feature is active
+        ft_history: History,
+        token_history: LookupMap<u64, History>,
+
+        // Time-based currency
+        time_rate: TimeRate,
+        balance_indices: LookupMap<AccountId, u128>, // time index each balance was last settled at
+        token_unlock_times: LookupMap<u64, u64>,     // grandchild tokens locked until a timestamp
+    }
+
+    #[cfg(feature = "scts777")]
+    impl_fungible_token_standard!(SCTS777);
+    #[cfg(feature = "scts777")]
+    impl_pausable!(SCTS777);
+    #[cfg(feature = "scts777")]
+    impl_access_control!(SCTS777);
+    #[cfg(feature = "scts777")]
+    impl_feature_flags!(SCTS777);
+    #[cfg(feature = "scts777")]
+    impl_ft_history!(SCTS777);
+
+    // State versioning
+    // The state version is stored under its own key, since state written before versioning
+    // has no version tag. A missing version means version 1.
+    #[cfg(feature = "scts777")]
+    const SCTS777_STATE_VERSION: u16 = 2;
+    #[cfg(feature = "scts777")]
+    const STATE_VERSION_KEY: &[u8] = b"STATE_VERSION";
+    // Prefix of the version 1 parent -> grandchild token map, read by migrate_grandchild_tokens
+    #[cfg(feature = "scts777")]
+    const V1_GRANDCHILD_TOKENS_PREFIX: &[u8] = b"g";
+
+    #[cfg(feature = "scts777")]
+    fn read_state_version() -> u16 {
+        env::storage_read(STATE_VERSION_KEY)
+            .map(|version| u16::try_from_slice(&version).expect("Invalid state version"))
+            .unwrap_or(1)
+    }
+
+    #[cfg(feature = "scts777")]
+    fn write_state_version(version: u16) {
+        env::storage_write(STATE_VERSION_KEY, &version.try_to_vec().unwrap());
+    }
+
+    // SCTS777 state as deployed before versioning. Fields that are not carried over are
+    // still declared so the layout matches.
+    #[cfg(feature = "scts777")]
+    #[allow(dead_code)]
+    #[derive(BorshSerialize, BorshDeserialize)]
+    pub struct SCTS777V1 {
+        name: String,
+        symbol: String,
+        total_supply: Balance,
+        balances: LookupMap<AccountId, Balance>,
+        allowances: LookupMap<AccountId, LookupMap<AccountId, Balance>>,
+        next_token_id: u64,
+        token_owners: LookupMap<u64, AccountId>,
+        token_balances: LookupMap<AccountId, LookupMap<u64, Balance>>,
+        token_approvals: LookupMap<u64, AccountId>,
+        grandchild_tokens: LookupMap<u64, LookupMap<u64, u64>>,
+    }
+
+    // Only ever read once, in migrate
+    #[cfg(feature = "scts777")]
+    #[allow(clippy::large_enum_variant)]
+    pub enum VersionedSCTS777 {
+        V1(SCTS777V1),
+        V2(SCTS777),
+    }
+
+    #[cfg(feature = "scts777")]
+    impl VersionedSCTS777 {
+        fn read() -> Self {
+            match read_state_version() {
+                1 => VersionedSCTS777::V1(env::state_read().expect("Contract state not found")),
+                2 => VersionedSCTS777::V2(env::state_read().expect("Contract state not found")),
+                version => env::panic(format!("Unknown state version {}", version).as_bytes()),
+            }
+        }
+    }
+    
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    impl SCTS777 {
+        // Constructor
+        #[init]
+        pub fn new(name: String, symbol: String, total_supply: Balance) -> Self {
+            let owner_id = env::predecessor_account_id();
+            let mut instance = Self::internal_new(name, symbol, total_supply, owner_id.clone());
+            instance.balances.insert(&owner_id, &total_supply);
+            instance.balance_indices.insert(&owner_id, &TIME_INDEX_SCALE);
+            instance.internal_record_ft(None, Some(&owner_id), total_supply, TransactionReason::Mint);
+            log_ft_event("ft_mint", json!({ "owner_id": owner_id, "amount": U128(total_supply) }));
+            instance
+        }
+
+        // Builds empty state and records it as the current state version
+        fn internal_new(name: String, symbol: String, total_supply: Balance, owner_id: AccountId) -> Self {
+            let mut instance = Self {
+                name,
+                symbol,
+                total_supply,
+                balances: LookupMap::new(StorageKey::Balances),
+                allowances: LookupMap::new(StorageKey::Allowances),
+                next_token_id: 0,
+                token_owners: LookupMap::new(StorageKey::TokenOwners),
+                token_balances: LookupMap::new(StorageKey::TokenBalances),
+                token_approvals: LookupMap::new(StorageKey::TokenApprovals),
+                next_approval_id: LookupMap::new(StorageKey::NextApprovalId),
+                grandchild_tokens: LookupMap::new(StorageKey::GrandchildTokens),
+                grandchild_token_ids: UnorderedSet::new(StorageKey::GrandchildTokenIds),
+                tokens_per_owner: LookupMap::new(StorageKey::TokensPerOwner),
+                storage_balances: LookupMap::new(StorageKey::StorageBalances),
+                account_storage_usage: 0,
+                token_storage_payers: LookupMap::new(StorageKey::TokenStoragePayers),
+                paused_functions: HashMap::new(),
+                owner_id,
+                pending_owner_id: None,
+                role_members: LookupMap::new(StorageKey::RoleMembers),
+                active_features: Feature::ALL.iter().copied().collect(),
+                token_metadata: LookupMap::new(StorageKey::TokenMetadata),
+                ft_history: History::new(StorageKey::FtHistory, MAX_FT_HISTORY_LEN),
+                token_history: LookupMap::new(StorageKey::TokenHistory),
+                time_rate: TimeRate {
+                    rate_bps: 0,
+                    period: U64(1),
+                    index: U128(TIME_INDEX_SCALE),
+                    updated_at: U64(env::block_timestamp()),
+                },
+                balance_indices: LookupMap::new(StorageKey::BalanceIndices),
+                token_unlock_times: LookupMap::new(StorageKey::TokenUnlockTimes),
+            };
+            instance.measure_account_storage_usage();
+            write_state_version(SCTS777_STATE_VERSION);
+            instance
+        }
+    
+        // Standard functions
+        // ...
+
+        // Functions for self-fractalization
+        // ...
+
+        // Functions for funible tokens
+        // NEP-141 and NEP-148 come from impl_fungible_token_standard!
+    
+        // Functions for non-fungible tokens
+        // Grandchild tokens are exposed through NEP-171 (core), NEP-177 (metadata),
+        // NEP-178 (approvals) and NEP-181 (enumeration).
+
+        #[payable]
+        pub fn nft_transfer(
+            &mut self,
+            receiver_id: ValidAccountId,
+            token_id: String,
+            approval_id: Option<u64>,
+            memo: Option<String>,
+        ) {
+            assert_one_yocto();
+            let sender_id = env::predecessor_account_id();
+            self.internal_nft_transfer(&sender_id, receiver_id.as_ref(), parse_token_id(&token_id), approval_id, memo);
+        }
+
+        // Transfers to a contract and calls its nft_on_transfer; the token is returned
+        // in nft_resolve_transfer if the receiver asks for it.
+        #[payable]
+        pub fn nft_transfer_call(
+            &mut self,
+            receiver_id: ValidAccountId,
+            token_id: String,
+            approval_id: Option<u64>,
+            memo: Option<String>,
+            msg: String,
+        ) -> PromiseOrValue<bool> {
+            assert_one_yocto();
+            self.assert_feature_active(Feature::Composability);
+            assert!(
+                env::prepaid_gas() > GAS_FOR_NFT_TRANSFER_CALL,
+                "More gas is required"
+            );
+            let sender_id = env::predecessor_account_id();
+            let receiver_id: AccountId = receiver_id.into();
+            let (previous_owner_id, approved_account_ids) = self.internal_nft_transfer(
+                &sender_id,
+                &receiver_id,
+                parse_token_id(&token_id),
+                approval_id,
+                memo,
+            );
+
+            ext_non_fungible_token_receiver::nft_on_transfer(
+                sender_id,
+                previous_owner_id.clone(),
+                token_id.clone(),
+                msg,
+                &receiver_id,
+                NO_DEPOSIT,
+                env::prepaid_gas() - GAS_FOR_NFT_TRANSFER_CALL,
+            )
+            .then(ext_non_fungible_token_resolver::nft_resolve_transfer(
+                previous_owner_id,
+                receiver_id,
+                token_id,
+                approved_account_ids,
+                &env::current_account_id(),
+                NO_DEPOSIT,
+                GAS_FOR_RESOLVE_TRANSFER,
+            ))
+            .into()
+        }
+
+        // Returns the token to its previous owner, with its old approvals, if nft_on_transfer
+        // asked for it and the receiver still owns it. Returns whether the transfer stands.
+        #[private]
+        pub fn nft_resolve_transfer(
+            &mut self,
+            previous_owner_id: AccountId,
+            receiver_id: AccountId,
+            token_id: String,
+            approved_account_ids: Option<HashMap<AccountId, u64>>,
+        ) -> bool {
+            let must_revert = match env::promise_result(0) {
+                PromiseResult::NotReady => unreachable!(),
+                PromiseResult::Successful(value) => near_sdk::serde_json::from_slice::<bool>(&value).unwrap_or(true),
+                PromiseResult::Failed => true,
+            };
+            if !must_revert {
+                return true;
+            }
+
+            let grandchild_token_id = parse_token_id(&token_id);
+            if self.token_owners.get(&grandchild_token_id) != Some(receiver_id.clone()) {
+                // The receiver already burned or passed on the token
+                return true;
+            }
+            // The callback must not fail, so the bytes the return adds are not charged
+            self.internal_move_grandchild_token(grandchild_token_id, &receiver_id, &previous_owner_id);
+            if let Some(approved_account_ids) = approved_account_ids {
+                // The approvals are restored only if the previous owner can pay for them again
+                let initial_storage_usage = env::storage_usage();
+                self.token_approvals.insert(&grandchild_token_id, &approved_account_ids);
+                if self
+                    .internal_try_charge_storage(&previous_owner_id, env::storage_usage() - initial_storage_usage)
+                    .is_err()
+                {
+                    self.token_approvals.remove(&grandchild_token_id);
+                }
+            }
+            log_nft_event(
+                "nft_transfer",
+                json!({
+                    "old_owner_id": receiver_id,
+                    "new_owner_id": previous_owner_id,
+                    "token_ids": [token_id],
+                }),
+            );
+            false
+        }
+
+        pub fn nft_token(&self, token_id: String) -> Option<JsonToken> {
+            self.internal_json_token(parse_token_id(&token_id))
+        }
+
+        pub fn nft_metadata(&self) -> NFTContractMetadata {
+            NFTContractMetadata {
+                spec: NFT_METADATA_SPEC.to_string(),
+                name: self.name.clone(),
+                symbol: self.symbol.clone(),
+                icon: None,
+                base_uri: None,
+                reference: None,
+                reference_hash: None,
+            }
+        }
+
+        // Approves `account_id` to transfer the token. If `msg` is given, the approved
+        // account's nft_on_approve is called with it.
+        #[payable]
+        pub fn nft_approve(&mut self, token_id: String, account_id: ValidAccountId, msg: Option<String>) -> Option<Promise> {
+            assert!(env::attached_deposit() >= 1, "Requires attached deposit of at least 1 yoctoNEAR");
+            let grandchild_token_id = parse_token_id(&token_id);
+            let (owner_id, approval_id) = self.internal_approve(grandchild_token_id, account_id.as_ref());
+
+            msg.map(|msg| {
+                ext_non_fungible_approval_receiver::nft_on_approve(
+                    token_id,
+                    owner_id,
+                    approval_id,
+                    msg,
+                    account_id.as_ref(),
+                    NO_DEPOSIT,
+                    env::prepaid_gas() - GAS_FOR_NFT_APPROVE,
+                )
+            })
+        }
+
+        #[payable]
+        pub fn nft_revoke(&mut self, token_id: String, account_id: ValidAccountId) {
+            assert_one_yocto();
+            self.internal_revoke(parse_token_id(&token_id), account_id.as_ref());
+        }
+
+        #[payable]
+        pub fn nft_revoke_all(&mut self, token_id: String) {
+            assert_one_yocto();
+            self.internal_revoke_all(parse_token_id(&token_id));
+        }
+
+        pub fn nft_is_approved(&self, token_id: String, approved_account_id: ValidAccountId, approval_id: Option<u64>) -> bool {
+            self.internal_is_approved(parse_token_id(&token_id), approved_account_id.as_ref(), approval_id)
+        }
+
+        pub fn nft_total_supply(&self) -> U128 {
+            U128(self.grandchild_token_ids.len() as u128)
+        }
+
+        pub fn nft_tokens(&self, from_index: Option<U128>, limit: Option<u64>) -> Vec<JsonToken> {
+            paginate(self.grandchild_token_ids.as_vector(), from_index, limit)
+                .into_iter()
+                .filter_map(|grandchild_token_id| self.internal_json_token(grandchild_token_id))
+                .collect()
+        }
+
+        pub fn nft_supply_for_owner(&self, account_id: ValidAccountId) -> U128 {
+            self.tokens_per_owner
+                .get(account_id.as_ref())
+                .map_or(U128(0), |tokens| U128(tokens.len() as u128))
+        }
+
+        pub fn nft_tokens_for_owner(&self, account_id: ValidAccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<JsonToken> {
+            self.get_tokens_for_owner(account_id, from_index, limit)
+                .into_iter()
+                .filter_map(|grandchild_token_id| self.internal_json_token(grandchild_token_id))
+                .collect()
+        }
+
+        // Lists the grandchild tokens of a token
+        pub fn get_child_tokens(&self, parent_token_id: u64, from_index: Option<U128>, limit: Option<u64>) -> Vec<u64> {
+            self.grandchild_tokens
+                .get(&parent_token_id)
+                .map_or_else(Vec::new, |grandchild_tokens| paginate(grandchild_tokens.as_vector(), from_index, limit))
+        }
+
+        // Lists the IDs of the tokens an account owns; nft_tokens_for_owner returns the full tokens
+        pub fn get_tokens_for_owner(&self, account_id: ValidAccountId, from_index: Option<U128>, limit: Option<u64>) -> Vec<u64> {
+            self.tokens_per_owner
+                .get(account_id.as_ref())
+                .map_or_else(Vec::new, |tokens| paginate(tokens.as_vector(), from_index, limit))
+        }
+
+        // Functions for grandchild tokens
+        // Mints a root token, which has no parent, to `to`. Grandchild tokens are minted under
+        // root tokens or under other grandchild tokens. Requires the Minter role.
+        pub fn mint_root_token(&mut self, to: ValidAccountId) -> u64 {
+            self.assert_not_paused(PausableFunction::Mint);
+            self.assert_feature_active(Feature::GrandchildMinting);
+            self.assert_role(Role::Minter);
+            let token_id = self.internal_mint_token(None, to.as_ref());
+            log_nft_event(
+                "nft_mint",
+                json!({ "owner_id": to, "token_ids": [token_id.to_string()] }),
+            );
+            token_id
+        }
+
+        // Mints a grandchild token of an existing token to `to`. Requires the Minter role.
+        pub fn generate_grandchild_token(&mut self, parent_token_id: u64, to: AccountId) -> Promise {
+            self.assert_not_paused(PausableFunction::Mint);
+            self.assert_feature_active(Feature::GrandchildMinting);
+            self.assert_role(Role::Minter);
+            assert!(
+                self.token_owners.contains_key(&parent_token_id),
+                "Parent token does not exist"
+            );
+            let grandchild_token_id = self.internal_mint_token(Some(parent_token_id), &to);
+            log_nft_event(
+                "nft_mint",
+                json!({ "owner_id": to, "token_ids": [grandchild_token_id.to_string()] }),
+            );
+
+            Promise::new(env::current_account_id()).as_return()
+        }
+
+        // Mints `count` grandchild tokens of an existing token to each registered account,
+        // charging the caller's storage balance for every token. Requires the Minter role.
+        pub fn batch_generate_grandchild_tokens(&mut self, parent_token_id: u64, mints: Vec<(AccountId, u64)>) -> BatchResult {
+            self.assert_not_paused(PausableFunction::Mint);
+            self.assert_feature_active(Feature::GrandchildMinting);
+            self.assert_role(Role::Minter);
+            assert!(
+                self.token_owners.contains_key(&parent_token_id),
+                "Parent token does not exist"
+            );
+
+            let mut batch = BatchResult { results: vec![], next_index: None };
+            let mut events = vec![];
+            for (index, (owner_id, count)) in mints.into_iter().enumerate() {
+                if !batch_has_gas_for(count.min(MAX_BATCH_MINT_COUNT)) {
+                    batch.next_index = Some(index as u64);
+                    break;
+                }
+                let result = if !env::is_valid_account_id(owner_id.as_bytes()) {
+                    BatchItemResult::Failed { reason: "Invalid account ID".to_string() }
+                } else if count == 0 || count > MAX_BATCH_MINT_COUNT {
+                    BatchItemResult::Failed {
+                        reason: format!("Count must be between 1 and {}", MAX_BATCH_MINT_COUNT),
+                    }
+                } else if !self.storage_balances.contains_key(&owner_id) {
+                    BatchItemResult::Failed { reason: "Account is not registered".to_string() }
+                } else {
+                    let token_ids: Vec<String> = (0..count)
+                        .map(|_| self.internal_mint_token(Some(parent_token_id), &owner_id).to_string())
+                        .collect();
+                    events.push(json!({ "owner_id": owner_id, "token_ids": token_ids }));
+                    BatchItemResult::Ok { token_ids }
+                };
+                batch.results.push(result);
+            }
+            if !events.is_empty() {
+                log_nft_events("nft_mint", events);
+            }
+            batch
+        }
+
+        // Burns grandchild tokens of `parent_token_id` owned by the caller
+        pub fn batch_burn_grandchild_tokens(&mut self, parent_token_id: u64, grandchild_token_ids: Vec<u64>) -> BatchResult {
+            self.assert_not_paused(PausableFunction::Burn);
+            let owner_id = env::predecessor_account_id();
+
+            let mut batch = BatchResult { results: vec![], next_index: None };
+            let mut burned_token_ids = vec![];
+            for (index, grandchild_token_id) in grandchild_token_ids.into_iter().enumerate() {
+                if !batch_has_gas_for(1) {
+                    batch.next_index = Some(index as u64);
+                    break;
+                }
+                let is_child_of_parent = self
+                    .grandchild_tokens
+                    .get(&parent_token_id)
+                    .is_some_and(|grandchild_tokens| grandchild_tokens.contains(&grandchild_token_id));
+                let result = if !is_child_of_parent {
+                    BatchItemResult::Failed { reason: "Grandchild token does not exist".to_string() }
+                } else if self.token_owners.get(&grandchild_token_id) != Some(owner_id.clone()) {
+                    BatchItemResult::Failed {
+                        reason: "Only the grandchild token owner can burn the token".to_string(),
+                    }
+                } else {
+                    self.internal_burn_grandchild(parent_token_id, grandchild_token_id, &owner_id);
+                    burned_token_ids.push(grandchild_token_id.to_string());
+                    BatchItemResult::Ok { token_ids: vec![grandchild_token_id.to_string()] }
+                };
+                batch.results.push(result);
+            }
+            if !burned_token_ids.is_empty() {
+                log_nft_event("nft_burn", json!({ "owner_id": owner_id, "token_ids": burned_token_ids }));
+            }
+            batch
+        }
+
+        // Transfers each grandchild token to its registered receiver on behalf of its owner or
+        // an approved caller, charging the caller once for the bytes the transfers add. Tokens
+        // that cannot be transferred are reported and skipped.
+        #[payable]
+        pub fn batch_transfer_grandchild_tokens(&mut self, transfers: Vec<(u64, ValidAccountId)>, memo: Option<String>) -> BatchResult {
+            assert_one_yocto();
+            self.assert_not_paused(PausableFunction::Transfer);
+            let sender_id = env::predecessor_account_id();
+
+            let mut batch = BatchResult { results: vec![], next_index: None };
+            let mut events = vec![];
+            let mut added_bytes = 0;
+            for (index, (grandchild_token_id, receiver_id)) in transfers.into_iter().enumerate() {
+                if !batch_has_gas_for(1) {
+                    batch.next_index = Some(index as u64);
+                    break;
+                }
+                let receiver_id: AccountId = receiver_id.into();
+                let result = match self.internal_check_transfer(&sender_id, &receiver_id, grandchild_token_id, None) {
+                    Ok(owner_id) => {
+                        added_bytes += self.internal_move_grandchild_token(grandchild_token_id, &owner_id, &receiver_id);
+                        let authorized_id = if sender_id != owner_id { Some(sender_id.clone()) } else { None };
+                        events.push(json!({
+                            "authorized_id": authorized_id,
+                            "old_owner_id": owner_id,
+                            "new_owner_id": receiver_id,
+                            "token_ids": [grandchild_token_id.to_string()],
+                            "memo": memo,
+                        }));
+                        BatchItemResult::Ok { token_ids: vec![grandchild_token_id.to_string()] }
+                    }
+                    Err(reason) => BatchItemResult::Failed { reason },
+                };
+                batch.results.push(result);
+            }
+            if !events.is_empty() {
+                log_nft_events("nft_transfer", events);
+            }
+            self.internal_charge_storage(&sender_id, added_bytes);
+            batch
+        }
+
+        // Mints a root token, or a grandchild token of an existing parent, to a registered
+        // account without logging. Root tokens are enumerated alongside grandchild tokens. The
+        // caller is charged for the bytes the token takes and recorded as their payer; the
+        // owner's balance map and token set are covered by its storage registration.
+        fn internal_mint_token(&mut self, parent_token_id: Option<u64>, to: &AccountId) -> u64 {
+            assert!(
+                self.storage_balances.contains_key(to),
+                "The receiver is not registered, call storage_deposit first"
+            );
+            let initial_storage_usage = env::storage_usage();
+            let grandchild_token_id = self.next_token_id;
+            self.next_token_id += 1;
+    
+            self.token_owners.insert(&grandchild_token_id, to);
+            let owner_collection_bytes = self.internal_add_token_to_owner(to, grandchild_token_id);
+    
+            if let Some(parent_token_id) = parent_token_id {
+                let mut grandchild_tokens = self.grandchild_tokens
+                    .get(&parent_token_id)
+                    .unwrap_or_else(|| UnorderedSet::new(StorageKey::GrandchildTokensPerParent { parent_token_id }));
+                grandchild_tokens.insert(&grandchild_token_id);
+                self.grandchild_tokens.insert(&parent_token_id, &grandchild_tokens);
+            }
+
+            self.grandchild_token_ids.insert(&grandchild_token_id);
+            let now = env::block_timestamp();
+            self.token_metadata.insert(
+                &grandchild_token_id,
+                &StoredTokenMetadata {
+                    parent_token_id,
+                    created_at: now,
+                    updated_at: now,
+                    ..Default::default()
+                },
+            );
+            self.internal_record_token(grandchild_token_id, None, Some(to), TransactionReason::Mint);
+
+            // The record has a fixed size, so it can be measured with a placeholder byte count
+            let mut token_storage = TokenStorage {
+                payer_id: env::predecessor_account_id(),
+                used_bytes: 0,
+                added_bytes: vec![],
+            };
+            self.token_storage_payers.insert(&grandchild_token_id, &token_storage);
+            token_storage.used_bytes = env::storage_usage() - initial_storage_usage - owner_collection_bytes;
+            self.token_storage_payers.insert(&grandchild_token_id, &token_storage);
+            self.internal_charge_storage(&token_storage.payer_id, token_storage.used_bytes);
+            grandchild_token_id
+        }
+    
+        // Moves grandchild tokens owned by `from` to `to`. The caller must be `from` or an
+        // account approved for every token; each approval is cleared by the transfer.
+        #[payable]
+        pub fn transfer_grandchild_tokens(&mut self, from: AccountId, to: ValidAccountId, grandchild_token_ids: Vec<u64>) -> Promise {
+            assert_one_yocto();
+            let sender_id = env::predecessor_account_id();
+            for grandchild_token_id in grandchild_token_ids {
+                self.internal_transfer_grandchild_from(&sender_id, &from, to.as_ref(), grandchild_token_id, None);
+            }
+
+            Promise::new(env::current_account_id()).as_return()
+        }
+
+        // Moves a single grandchild token owned by `from` to `to` on behalf of an approved
+        // caller, optionally checking the approval ID it was granted with.
+        #[payable]
+        pub fn transfer_from_grandchild_token(
+            &mut self,
+            from: AccountId,
+            to: ValidAccountId,
+            grandchild_token_id: u64,
+            approval_id: Option<u64>,
+        ) -> bool {
+            assert_one_yocto();
+            let sender_id = env::predecessor_account_id();
+            self.internal_transfer_grandchild_from(&sender_id, &from, to.as_ref(), grandchild_token_id, approval_id);
+            true
+        }
+
+        // Approvals for grandchild tokens
+        // A token can have several approved accounts, each with its own approval ID. IDs
+        // only ever increase per token, so a revoked and re-granted approval gets a new one.
+        // Transfers clear all approvals of the token. The nft_approve family uses the same store.
+
+        // Approves `account_id` to transfer the token and returns the approval ID
+        #[payable]
+        pub fn approve_grandchild_token(&mut self, grandchild_token_id: u64, account_id: ValidAccountId) -> u64 {
+            assert_one_yocto();
+            self.internal_approve(grandchild_token_id, account_id.as_ref()).1
+        }
+
+        #[payable]
+        pub fn revoke_grandchild_token(&mut self, grandchild_token_id: u64, account_id: ValidAccountId) {
+            assert_one_yocto();
+            self.internal_revoke(grandchild_token_id, account_id.as_ref());
+        }
+
+        #[payable]
+        pub fn revoke_all_grandchild_token(&mut self, grandchild_token_id: u64) {
+            assert_one_yocto();
+            self.internal_revoke_all(grandchild_token_id);
+        }
+
+        // Returns whether `account_id` is approved for the token, and with `approval_id` if given
+        pub fn is_approved_grandchild_token(&self, grandchild_token_id: u64, account_id: ValidAccountId, approval_id: Option<u64>) -> bool {
+            self.internal_is_approved(grandchild_token_id, account_id.as_ref(), approval_id)
+        }
+
+    pub fn burn_grandchild_token(&mut self, parent_token_id: u64, grandchild_token_id: u64) -> Promise {
+        self.assert_not_paused(PausableFunction::Burn);
+        let owner_id = env::predecessor_account_id();
+        assert!(
+            self.grandchild_tokens.contains_key(&parent_token_id),
+            "Parent token does not exist"
+        );
+        assert!(
+            self.grandchild_tokens
+                .get(&parent_token_id)
+                .unwrap()
+                .contains(&grandchild_token_id),
+            "Grandchild token does not exist"
+        );
+        let grandchild_owner_id = self.token_owners.get(&grandchild_token_id).unwrap();
+        assert_eq!(
+            grandchild_owner_id,
+            owner_id,
+            "Only the grandchild token owner can burn the token"
+        );
+        self.internal_burn_grandchild(parent_token_id, grandchild_token_id, &owner_id);
+        log_nft_event(
+            "nft_burn",
+            json!({ "owner_id": owner_id, "token_ids": [grandchild_token_id.to_string()] }),
+        );
+    
+        Promise::new(env::current_account_id()).as_return()
+    }
+
+    // Removes an owned grandchild token from every index without logging. Its approvals are
+    // released to the owner, who paid for them, and the rest of the freed bytes to the
+    // accounts charged for the token's storage, up to what each of them paid. Token IDs are
+    // never reused, so the approval ID counter can go as well.
+    fn internal_burn_grandchild(&mut self, parent_token_id: u64, grandchild_token_id: u64, owner_id: &AccountId) {
+        let approvals_storage_usage = env::storage_usage();
+        self.token_approvals.remove(&grandchild_token_id);
+        self.next_approval_id.remove(&grandchild_token_id);
+        self.internal_release_storage(owner_id, approvals_storage_usage - env::storage_usage());
+
+        let initial_storage_usage = env::storage_usage();
+        let token_storage = self.token_storage_payers.get(&grandchild_token_id);
+        // Remove the grandchild token from the grandchild_tokens list and the NFT indexes
+        let mut grandchild_tokens = self.grandchild_tokens.get(&parent_token_id).unwrap();
+        grandchild_tokens.remove(&grandchild_token_id);
+        if grandchild_tokens.is_empty() {
+            self.grandchild_tokens.remove(&parent_token_id);
+        } else {
+            self.grandchild_tokens.insert(&parent_token_id, &grandchild_tokens);
+        }
+        self.token_owners.remove(&grandchild_token_id);
+        self.token_metadata.remove(&grandchild_token_id);
+        self.token_unlock_times.remove(&grandchild_token_id);
+        self.grandchild_token_ids.remove(&grandchild_token_id);
+        self.token_storage_payers.remove(&grandchild_token_id);
+        let owner_collection_bytes = self.internal_remove_token_from_owner(owner_id, grandchild_token_id);
+        self.internal_record_token(grandchild_token_id, Some(owner_id), None, TransactionReason::Burn);
+
+        // The owner's balance map and token set are covered by its storage registration
+        let mut freed_bytes = initial_storage_usage.saturating_sub(env::storage_usage() + owner_collection_bytes);
+        if let Some(token_storage) = token_storage {
+            let payers = std::iter::once((token_storage.payer_id, token_storage.used_bytes)).chain(token_storage.added_bytes);
+            for (payer_id, used_bytes) in payers {
+                let released_bytes = freed_bytes.min(used_bytes);
+                self.internal_release_storage(&payer_id, released_bytes);
+                freed_bytes -= released_bytes;
+            }
+        }
+    }
+
+        // Transfers a grandchild token on behalf of its owner or an approved account and
+        // returns the previous owner together with the approvals the transfer cleared.
+        fn internal_nft_transfer(
+            &mut self,
+            sender_id: &AccountId,
+            receiver_id: &AccountId,
+            grandchild_token_id: u64,
+            approval_id: Option<u64>,
+            memo: Option<String>,
+        ) -> (AccountId, Option<HashMap<AccountId, u64>>) {
+            self.assert_not_paused(PausableFunction::Transfer);
+            let owner_id = self
+                .internal_check_transfer(sender_id, receiver_id, grandchild_token_id, approval_id)
+                .unwrap_or_else(|reason| env::panic(reason.as_bytes()));
+            let approved_account_ids = self.token_approvals.get(&grandchild_token_id);
+
+            let added_bytes = self.internal_move_grandchild_token(grandchild_token_id, &owner_id, receiver_id);
+            self.internal_charge_storage(sender_id, added_bytes);
+
+            let authorized_id = if sender_id != &owner_id { Some(sender_id.clone()) } else { None };
+            log_nft_event(
+                "nft_transfer",
+                json!({
+                    "authorized_id": authorized_id,
+                    "old_owner_id": owner_id,
+                    "new_owner_id": receiver_id,
+                    "token_ids": [grandchild_token_id.to_string()],
+                    "memo": memo,
+                }),
+            );
+            (owner_id, approved_account_ids)
+        }
+
+        // Checks that `sender_id` may transfer the token to `receiver_id` and returns its owner
+        fn internal_check_transfer(
+            &self,
+            sender_id: &AccountId,
+            receiver_id: &AccountId,
+            grandchild_token_id: u64,
+            approval_id: Option<u64>,
+        ) -> Result<AccountId, String> {
+            let owner_id = self
+                .token_owners
+                .get(&grandchild_token_id)
+                .ok_or_else(|| "Token not found".to_string())?;
+            if let Some(unlock_at) = self.token_unlock_times.get(&grandchild_token_id) {
+                if env::block_timestamp() < unlock_at {
+                    return Err(format!("Token is locked until {}", unlock_at));
+                }
+            }
+            if sender_id != &owner_id {
+                let actual_approval_id = self
+                    .token_approvals
+                    .get(&grandchild_token_id)
+                    .and_then(|approved_account_ids| approved_account_ids.get(sender_id).copied())
+                    .ok_or_else(|| "Sender not approved".to_string())?;
+                if let Some(approval_id) = approval_id {
+                    if actual_approval_id != approval_id {
+                        return Err(format!(
+                            "The actual approval_id {} is different from the given approval_id {}",
+                            actual_approval_id, approval_id
+                        ));
+                    }
+                }
+            }
+            if &owner_id == receiver_id {
+                return Err("The token owner and the receiver should be different".to_string());
+            }
+            if !self.storage_balances.contains_key(receiver_id) {
+                return Err("The receiver is not registered, call storage_deposit first".to_string());
+            }
+            Ok(owner_id)
+        }
+
+        // Transfers a grandchild token that must currently be owned by `from`
+        fn internal_transfer_grandchild_from(
+            &mut self,
+            sender_id: &AccountId,
+            from: &AccountId,
+            to: &AccountId,
+            grandchild_token_id: u64,
+            approval_id: Option<u64>,
+        ) {
+            let owner_id = self
+                .token_owners
+                .get(&grandchild_token_id)
+                .unwrap_or_else(|| env::panic(format!("Grandchild token {} does not exist", grandchild_token_id).as_bytes()));
+            assert_eq!(
+                &owner_id, from,
+                "Grandchild token {} is not owned by {}",
+                grandchild_token_id, from
+            );
+            self.internal_nft_transfer(sender_id, to, grandchild_token_id, approval_id, None);
+        }
+
+        // Moves a grandchild token between owners and clears its approvals, releasing them to
+        // the previous owner, who paid for them. Returns the bytes the move adds, such as a
+        // longer owner ID or a history record, for the caller to be charged for.
+        fn internal_move_grandchild_token(&mut self, grandchild_token_id: u64, from: &AccountId, to: &AccountId) -> StorageUsage {
+            let approvals_storage_usage = env::storage_usage();
+            self.token_approvals.remove(&grandchild_token_id);
+            self.internal_release_storage(from, approvals_storage_usage - env::storage_usage());
+
+            // The owners' balance maps and token sets are covered by their storage registration
+            let initial_storage_usage = env::storage_usage();
+            let freed_collection_bytes = self.internal_remove_token_from_owner(from, grandchild_token_id);
+            let added_collection_bytes = self.internal_add_token_to_owner(to, grandchild_token_id);
+            self.token_owners.insert(&grandchild_token_id, to);
+            self.internal_record_token(grandchild_token_id, Some(from), Some(to), TransactionReason::Transfer);
+            (env::storage_usage() + freed_collection_bytes).saturating_sub(initial_storage_usage + added_collection_bytes)
+        }
+
+        fn internal_record_token(
+            &mut self,
+            grandchild_token_id: u64,
+            from: Option<&AccountId>,
+            to: Option<&AccountId>,
+            reason: TransactionReason,
+        ) {
+            if !self.active_features.contains(&Feature::History) {
+                return;
+            }
+            let mut history = self
+                .token_history
+                .get(&grandchild_token_id)
+                .unwrap_or_else(|| {
+                    History::new(StorageKey::TokenHistoryPerToken { token_id: grandchild_token_id }, MAX_TOKEN_HISTORY_LEN)
+                });
+            history.push(&Transaction::new(from, to, 1, reason));
+            self.token_history.insert(&grandchild_token_id, &history);
+        }
+
+        // Adds a token to the owner's balance map and token set, creating both for a new owner.
+        // Returns the bytes the created collections take.
+        fn internal_add_token_to_owner(&mut self, owner_id: &AccountId, grandchild_token_id: u64) -> StorageUsage {
+            let initial_storage_usage = env::storage_usage();
+            let account_hash = env::sha256(owner_id.as_bytes());
+            if !self.token_balances.contains_key(owner_id) {
+                let balances = LookupMap::new(StorageKey::TokenBalancesPerOwner { account_hash: account_hash.clone() });
+                self.token_balances.insert(owner_id, &balances);
+            }
+            if !self.tokens_per_owner.contains_key(owner_id) {
+                self.tokens_per_owner.insert(owner_id, &UnorderedSet::new(StorageKey::TokensPerOwnerInner { account_hash }));
+            }
+            let collection_bytes = env::storage_usage() - initial_storage_usage;
+
+            let mut balances = self.token_balances.get(owner_id).unwrap();
+            balances.insert(&grandchild_token_id, &1);
+            let mut tokens = self.tokens_per_owner.get(owner_id).unwrap();
+            tokens.insert(&grandchild_token_id);
+            self.tokens_per_owner.insert(owner_id, &tokens);
+            collection_bytes
+        }
+
+        // Removes a token from the owner's balance map and token set, removing both once the
+        // owner holds no more tokens. Returns the bytes the removed collections took.
+        fn internal_remove_token_from_owner(&mut self, owner_id: &AccountId, grandchild_token_id: u64) -> StorageUsage {
+            let mut balances = self.token_balances.get(owner_id).expect("Owner has no tokens");
+            balances
+                .remove(&grandchild_token_id)
+                .expect("Token not found in the owner's balance");
+            // Owners carried over from version 1 keep their balance map until they are indexed
+            let mut tokens = match self.tokens_per_owner.get(owner_id) {
+                Some(tokens) => tokens,
+                None => return 0,
+            };
+            tokens.remove(&grandchild_token_id);
+            if !tokens.is_empty() {
+                self.tokens_per_owner.insert(owner_id, &tokens);
+                return 0;
+            }
+            let initial_storage_usage = env::storage_usage();
+            self.token_balances.remove(owner_id);
+            self.tokens_per_owner.remove(owner_id);
+            initial_storage_usage - env::storage_usage()
+        }
+
+        // Adds or renews an approval and returns the token owner and the new approval ID
+        fn internal_approve(&mut self, grandchild_token_id: u64, account_id: &AccountId) -> (AccountId, u64) {
+            self.assert_not_paused(PausableFunction::Approve);
+            let owner_id = self.internal_assert_token_owner(grandchild_token_id);
+            let initial_storage_usage = env::storage_usage();
+
+            let approval_id = self.next_approval_id.get(&grandchild_token_id).unwrap_or(1);
+            let mut approved_account_ids = self.token_approvals.get(&grandchild_token_id).unwrap_or_default();
+            approved_account_ids.insert(account_id.clone(), approval_id);
+            self.token_approvals.insert(&grandchild_token_id, &approved_account_ids);
+            self.next_approval_id.insert(&grandchild_token_id, &(approval_id + 1));
+            self.internal_settle_storage(&owner_id, initial_storage_usage);
+
+            (owner_id, approval_id)
+        }
+
+        fn internal_revoke(&mut self, grandchild_token_id: u64, account_id: &AccountId) {
+            let owner_id = self.internal_assert_token_owner(grandchild_token_id);
+            let initial_storage_usage = env::storage_usage();
+
+            if let Some(mut approved_account_ids) = self.token_approvals.get(&grandchild_token_id) {
+                approved_account_ids.remove(account_id);
+                if approved_account_ids.is_empty() {
+                    self.token_approvals.remove(&grandchild_token_id);
+                } else {
+                    self.token_approvals.insert(&grandchild_token_id, &approved_account_ids);
+                }
+            }
+            self.internal_settle_storage(&owner_id, initial_storage_usage);
+        }
+
+        fn internal_revoke_all(&mut self, grandchild_token_id: u64) {
+            let owner_id = self.internal_assert_token_owner(grandchild_token_id);
+            let initial_storage_usage = env::storage_usage();
+            self.token_approvals.remove(&grandchild_token_id);
+            self.internal_settle_storage(&owner_id, initial_storage_usage);
+        }
+
+        fn internal_is_approved(&self, grandchild_token_id: u64, account_id: &AccountId, approval_id: Option<u64>) -> bool {
+            assert!(self.token_owners.contains_key(&grandchild_token_id), "Token not found");
+            match self
+                .token_approvals
+                .get(&grandchild_token_id)
+                .and_then(|approved_account_ids| approved_account_ids.get(account_id).copied())
+            {
+                Some(actual_approval_id) => approval_id.is_none_or(|approval_id| approval_id == actual_approval_id),
+                None => false,
+            }
+        }
+
+        fn internal_assert_token_owner(&self, grandchild_token_id: u64) -> AccountId {
+            let owner_id = self.token_owners.get(&grandchild_token_id).expect("Token not found");
+            assert_eq!(
+                env::predecessor_account_id(),
+                owner_id,
+                "Only the token owner can change approvals"
+            );
+            owner_id
+        }
+
+        fn internal_json_token(&self, grandchild_token_id: u64) -> Option<JsonToken> {
+            let owner_id = self.token_owners.get(&grandchild_token_id)?;
+            let metadata = self.internal_resolve_metadata(grandchild_token_id);
+            Some(JsonToken {
+                token_id: grandchild_token_id.to_string(),
+                owner_id,
+                metadata: Some(TokenMetadata {
+                    title: Some(
+                        metadata
+                            .title
+                            .unwrap_or_else(|| format!("{} #{}", self.symbol, grandchild_token_id)),
+                    ),
+                    description: metadata.description,
+                    media: metadata.media,
+                    media_hash: metadata.media_hash,
+                    copies: Some(1),
+                    issued_at: Some(metadata.created_at.0.to_string()),
+                    updated_at: Some(metadata.updated_at.0.to_string()),
+                    extra: if metadata.attributes.is_empty() {
+                        None
+                    } else {
+                        Some(json!(metadata.attributes).to_string())
+                    },
+                    ..Default::default()
+                }),
+                approved_account_ids: Some(self.token_approvals.get(&grandchild_token_id).unwrap_or_default()),
+            })
+        }
+    }
+
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    impl SCTS777 {
+        // Storage management (NEP-145)
+        // Minting, approving and transferring charge the caller's storage balance for the bytes
+        // they add; burning and revoking release the freed bytes back to whoever paid for them.
+        // Accounts must be registered to hold tokens, and the minimum balance covers the
+        // balance map and token set every holder gets.
+
+        // Registers `account_id` (the caller by default) or tops up its storage balance.
+        // With `registration_only`, only the minimum balance is kept and the rest is refunded.
+        #[payable]
+        pub fn storage_deposit(&mut self, account_id: Option<ValidAccountId>, registration_only: Option<bool>) -> StorageBalance {
+            let amount = env::attached_deposit();
+            let account_id: AccountId = account_id.map(|a| a.into()).unwrap_or_else(env::predecessor_account_id);
+            let registration_only = registration_only.unwrap_or(false);
+
+            let refund = match self.storage_balances.get(&account_id) {
+                Some(mut account_storage) => {
+                    if registration_only {
+                        amount
+                    } else {
+                        account_storage.deposit += amount;
+                        self.storage_balances.insert(&account_id, &account_storage);
+                        0
+                    }
+                }
+                None => {
+                    let min_balance = self.storage_balance_bounds().min.0;
+                    assert!(
+                        amount >= min_balance,
+                        "The attached deposit is less than the minimum storage balance"
+                    );
+                    let deposit = if registration_only { min_balance } else { amount };
+                    self.storage_balances.insert(
+                        &account_id,
+                        &AccountStorage {
+                            deposit,
+                            used_bytes: self.account_storage_usage,
+                        },
+                    );
+                    amount - deposit
+                }
+            };
+            if refund > 0 {
+                Promise::new(env::predecessor_account_id()).transfer(refund);
+            }
+            self.internal_storage_balance_of(&account_id).unwrap()
+        }
+
+        // Withdraws `amount` (all available by default) of the caller's unused storage balance.
+        #[payable]
+        pub fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+            assert_one_yocto();
+            let account_id = env::predecessor_account_id();
+            let mut account_storage = self
+                .storage_balances
+                .get(&account_id)
+                .expect("The account is not registered");
+            let available = self.internal_storage_available(&account_storage);
+            let amount = amount.map_or(available, |amount| amount.0);
+            assert!(amount <= available, "The amount is greater than the available storage balance");
+
+            account_storage.deposit -= amount;
+            self.storage_balances.insert(&account_id, &account_storage);
+            if amount > 0 {
+                Promise::new(account_id.clone()).transfer(amount);
+            }
+            self.internal_storage_balance_of(&account_id).unwrap()
+        }
+
+        // Unregisters the caller and refunds its whole deposit. Only accounts that hold no
+        // tokens and no longer pay for any tokens or approvals can unregister.
+        #[payable]
+        pub fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+            assert_one_yocto();
+            assert!(!force.unwrap_or(false), "Forced unregistration is not supported");
+            let account_id = env::predecessor_account_id();
+            match self.storage_balances.get(&account_id) {
+                Some(account_storage) => {
+                    assert!(!self.tokens_per_owner.contains_key(&account_id), "The account still holds tokens");
+                    assert_eq!(
+                        account_storage.used_bytes, self.account_storage_usage,
+                        "The account still pays for stored tokens or approvals"
+                    );
+                    self.storage_balances.remove(&account_id);
+                    Promise::new(account_id).transfer(account_storage.deposit);
+                    true
+                }
+                None => false,
+            }
+        }
+
+        pub fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+            StorageBalanceBounds {
+                min: U128(Balance::from(self.account_storage_usage) * env::storage_byte_cost()),
+                max: None,
+            }
+        }
+
+        pub fn storage_balance_of(&self, account_id: ValidAccountId) -> Option<StorageBalance> {
+            self.internal_storage_balance_of(account_id.as_ref())
+        }
+
+        fn internal_storage_balance_of(&self, account_id: &AccountId) -> Option<StorageBalance> {
+            self.storage_balances
+                .get(account_id)
+                .map(|account_storage| StorageBalance {
+                    total: U128(account_storage.deposit),
+                    available: U128(self.internal_storage_available(&account_storage)),
+                })
+        }
+
+        fn internal_storage_available(&self, account_storage: &AccountStorage) -> Balance {
+            account_storage
+                .deposit
+                .saturating_sub(Balance::from(account_storage.used_bytes) * env::storage_byte_cost())
+        }
+
+        // Charges `account_id` for the bytes added since `initial_storage_usage`, or releases
+        // the bytes freed since then back to its storage balance.
+        fn internal_settle_storage(&mut self, account_id: &AccountId, initial_storage_usage: StorageUsage) {
+            let current_storage_usage = env::storage_usage();
+            if current_storage_usage < initial_storage_usage {
+                self.internal_release_storage(account_id, initial_storage_usage - current_storage_usage);
+            } else {
+                self.internal_charge_storage(account_id, current_storage_usage - initial_storage_usage);
+            }
+        }
+
+        fn internal_charge_storage(&mut self, account_id: &AccountId, added_bytes: StorageUsage) {
+            if let Err(reason) = self.internal_try_charge_storage(account_id, added_bytes) {
+                env::panic(reason.as_bytes());
+            }
+        }
+
+        // Charges `account_id` for `added_bytes`, leaving its storage balance unchanged if it
+        // is not registered or its deposit does not cover them
+        fn internal_try_charge_storage(&mut self, account_id: &AccountId, added_bytes: StorageUsage) -> Result<(), &'static str> {
+            if added_bytes == 0 {
+                return Ok(());
+            }
+            let mut account_storage = self
+                .storage_balances
+                .get(account_id)
+                .ok_or("The account is not registered, call storage_deposit first")?;
+            account_storage.used_bytes += added_bytes;
+            if account_storage.deposit < Balance::from(account_storage.used_bytes) * env::storage_byte_cost() {
+                return Err("Not enough storage balance, call storage_deposit to add more");
+            }
+            self.storage_balances.insert(account_id, &account_storage);
+            Ok(())
+        }
+
+        // Charges `account_id` for the bytes a token gained since `initial_storage_usage` and
+        // records them on the token, so they are released to it when the token is burned.
+        // Freed bytes are released only up to what the account was charged for the token.
+        fn internal_settle_token_storage(&mut self, token_id: u64, account_id: &AccountId, initial_storage_usage: StorageUsage) {
+            let mut token_storage = self.token_storage_payers.get(&token_id).unwrap_or_else(|| TokenStorage {
+                // Tokens carried over from version 1 were not charged to anyone when minted
+                payer_id: env::current_account_id(),
+                used_bytes: 0,
+                added_bytes: vec![],
+            });
+            let index = match token_storage.added_bytes.iter().position(|(payer_id, _)| payer_id == account_id) {
+                Some(index) => index,
+                None => {
+                    token_storage.added_bytes.push((account_id.clone(), 0));
+                    token_storage.added_bytes.len() - 1
+                }
+            };
+            // Byte counts have a fixed size, so the record can be measured before it is final
+            self.token_storage_payers.insert(&token_id, &token_storage);
+            let current_storage_usage = env::storage_usage();
+            if current_storage_usage >= initial_storage_usage {
+                token_storage.added_bytes[index].1 += current_storage_usage - initial_storage_usage;
+                self.token_storage_payers.insert(&token_id, &token_storage);
+                self.internal_charge_storage(account_id, current_storage_usage - initial_storage_usage);
+                return;
+            }
+            let released_bytes = (initial_storage_usage - current_storage_usage).min(token_storage.added_bytes[index].1);
+            token_storage.added_bytes[index].1 -= released_bytes;
+            if token_storage.added_bytes[index].1 == 0 {
+                token_storage.added_bytes.remove(index);
+            }
+            self.token_storage_payers.insert(&token_id, &token_storage);
+            self.internal_release_storage(account_id, released_bytes);
+        }
+
+        // Returns freed bytes to the storage balance of `account_id`. Accounts that are not
+        // registered pay for nothing, so there is nothing to release.
+        fn internal_release_storage(&mut self, account_id: &AccountId, freed_bytes: StorageUsage) {
+            if freed_bytes == 0 {
+                return;
+            }
+            if let Some(mut account_storage) = self.storage_balances.get(account_id) {
+                account_storage.used_bytes = account_storage
+                    .used_bytes
+                    .saturating_sub(freed_bytes)
+                    .max(self.account_storage_usage);
+                self.storage_balances.insert(account_id, &account_storage);
+            }
+        }
+
+        // Measures the bytes one registered account takes in `storage_balances`, together with
+        // the balance map and token set it gets once it holds tokens
+        fn measure_account_storage_usage(&mut self) {
+            let initial_storage_usage = env::storage_usage();
+            let tmp_account_id = "a".repeat(64);
+            self.storage_balances.insert(&tmp_account_id, &AccountStorage::default());
+            let account_bytes = env::storage_usage() - initial_storage_usage;
+            self.account_storage_usage = account_bytes + self.internal_add_token_to_owner(&tmp_account_id, 0);
+            self.internal_remove_token_from_owner(&tmp_account_id, 0);
+            self.storage_balances.remove(&tmp_account_id);
+        }
+    }
+    
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    impl SCTS777 {
+        // Token metadata
+        // Metadata can be changed by the token owner or a metadata manager and is charged to
+        // the token owner's storage balance. Fields a token leaves unset are read from its
+        // parent token, recursively.
+
+        // Replaces all editable metadata of a token, keeping its parent and creation time
+        pub fn set_token_metadata(&mut self, token_id: u64, metadata: TokenMetadataPatch) {
+            self.internal_update_metadata(token_id, metadata, true);
+        }
+
+        // Changes only the metadata fields present in `patch`
+        pub fn patch_token_metadata(&mut self, token_id: u64, patch: TokenMetadataPatch) {
+            self.internal_update_metadata(token_id, patch, false);
+        }
+
+        pub fn get_token_metadata(&self, token_id: u64) -> Option<TokenMetadataView> {
+            if !self.token_owners.contains_key(&token_id) {
+                return None;
+            }
+            Some(self.internal_resolve_metadata(token_id))
+        }
+
+        fn internal_update_metadata(&mut self, token_id: u64, patch: TokenMetadataPatch, replace: bool) {
+            self.assert_feature_active(Feature::Metadata);
+            let owner_id = self.token_owners.get(&token_id).expect("Token does not exist");
+            if env::predecessor_account_id() != owner_id {
+                self.assert_role(Role::MetadataManager);
+            }
+            let initial_storage_usage = env::storage_usage();
+
+            let mut metadata = self.token_metadata.get(&token_id).unwrap_or_else(|| StoredTokenMetadata {
+                created_at: env::block_timestamp(),
+                ..Default::default()
+            });
+            if replace {
+                metadata.title = None;
+                metadata.description = None;
+                metadata.media = None;
+                metadata.media_hash = None;
+                metadata.attributes.clear();
+            }
+            if let Some(title) = patch.title {
+                assert!(title.len() <= MAX_METADATA_TITLE_LEN, "Title is too long");
+                metadata.title = Some(title).filter(|title| !title.is_empty());
+            }
+            if let Some(description) = patch.description {
+                assert!(description.len() <= MAX_METADATA_DESCRIPTION_LEN, "Description is too long");
+                metadata.description = Some(description).filter(|description| !description.is_empty());
+            }
+            if let Some(media) = patch.media {
+                assert!(media.len() <= MAX_METADATA_MEDIA_LEN, "Media URI is too long");
+                metadata.media = Some(media).filter(|media| !media.is_empty());
+                metadata.media_hash = None;
+            }
+            if let Some(media_hash) = patch.media_hash {
+                assert_eq!(media_hash.0.len(), METADATA_MEDIA_HASH_LEN, "Media hash must be a sha256 hash");
+                assert!(metadata.media.is_some(), "Media hash requires a media URI");
+                metadata.media_hash = Some(media_hash.0);
+            }
+            for (key, value) in patch.attributes.unwrap_or_default() {
+                assert!(key.len() <= MAX_METADATA_ATTRIBUTE_KEY_LEN, "Attribute key is too long");
+                match value {
+                    Some(value) => {
+                        assert!(value.len() <= MAX_METADATA_ATTRIBUTE_VALUE_LEN, "Attribute value is too long");
+                        metadata.attributes.insert(key, value);
+                    }
+                    None => {
+                        metadata.attributes.remove(&key);
+                    }
+                }
+            }
+            assert!(metadata.attributes.len() <= MAX_METADATA_ATTRIBUTES, "Too many attributes");
+            metadata.updated_at = env::block_timestamp();
+
+            self.token_metadata.insert(&token_id, &metadata);
+            self.internal_settle_token_storage(token_id, &owner_id, initial_storage_usage);
+            log_scts777_event(
+                "token_metadata_update",
+                json!({ "token_ids": [token_id.to_string()], "updated_by": env::predecessor_account_id() }),
+            );
+        }
+
+        // Returns the token's metadata with every unset field taken from the nearest ancestor
+        // that sets it. Attributes are merged, with the token's own values taking precedence.
+        fn internal_resolve_metadata(&self, token_id: u64) -> TokenMetadataView {
+            let metadata = self.token_metadata.get(&token_id).unwrap_or_default();
+            let mut view = TokenMetadataView {
+                title: metadata.title,
+                description: metadata.description,
+                media: metadata.media,
+                media_hash: metadata.media_hash.map(Base64VecU8),
+                attributes: metadata.attributes,
+                parent_token_id: metadata.parent_token_id,
+                created_at: U64(metadata.created_at),
+                updated_at: U64(metadata.updated_at),
+            };
+
+            let mut ancestor_id = metadata.parent_token_id;
+            while let Some(parent) = ancestor_id.and_then(|parent_token_id| self.token_metadata.get(&parent_token_id)) {
+                view.title = view.title.or(parent.title);
+                view.description = view.description.or(parent.description);
+                if view.media.is_none() {
+                    view.media = parent.media;
+                    view.media_hash = parent.media_hash.map(Base64VecU8);
+                }
+                for (key, value) in parent.attributes {
+                    view.attributes.entry(key).or_insert(value);
+                }
+                ancestor_id = parent.parent_token_id;
+            }
+            view
+        }
+    }
+
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    impl SCTS777 {
+        // Time-based currency
+        // Fungible balances follow the time rate: each stored balance is scaled by how much
+        // the time index grew or shrank since it was last settled. Balances are settled
+        // whenever they change, so ft_total_supply covers settled balances only.
+
+        // Sets the rate in basis points per `period` nanoseconds, negative for decay.
+        // Growth up to now is kept at the old rate. Requires the Admin role.
+        pub fn set_time_rate(&mut self, rate_bps: i32, period: U64) {
+            self.assert_role(Role::Admin);
+            assert!(period.0 > 0, "Period must be positive");
+            assert!(
+                rate_bps.unsigned_abs() as u128 <= BASIS_POINTS,
+                "Rate must be within 10000 basis points per period"
+            );
+            let now = env::block_timestamp();
+            self.time_rate = TimeRate {
+                rate_bps,
+                period,
+                index: U128(self.time_rate.index_at(now)),
+                updated_at: U64(now),
+            };
+            log_scts777_event(
+                "time_rate_update",
+                json!({ "rate_bps": rate_bps, "period": period, "index": self.time_rate.index }),
+            );
+        }
+
+        pub fn get_time_rate(&self) -> TimeRate {
+            self.time_rate.clone()
+        }
+
+        // Returns the balance `account_id` will have at `timestamp` under the current rate,
+        // for timestamps from the last rate change or settlement of the balance onwards
+        pub fn ft_balance_at(&self, account_id: ValidAccountId, timestamp: U64) -> U128 {
+            self.internal_balance_at(account_id.as_ref(), timestamp.0).into()
+        }
+
+        // Locks a grandchild token against transfers until `unlock_at`. Only the owner can
+        // lock a token, so a minter can lock the tokens it minted only while it still holds
+        // them. A lock can only be extended, and its storage is charged to the owner.
+        pub fn lock_grandchild_token(&mut self, grandchild_token_id: u64, unlock_at: U64) {
+            let owner_id = self.token_owners.get(&grandchild_token_id).expect("Token not found");
+            assert_eq!(
+                env::predecessor_account_id(),
+                owner_id,
+                "Only the token owner can lock the token"
+            );
+            let current_unlock_at = self.token_unlock_times.get(&grandchild_token_id).unwrap_or(0);
+            assert!(unlock_at.0 > current_unlock_at, "A lock can only be extended");
+            let initial_storage_usage = env::storage_usage();
+            self.token_unlock_times.insert(&grandchild_token_id, &unlock_at.0);
+            self.internal_settle_token_storage(grandchild_token_id, &owner_id, initial_storage_usage);
+            log_scts777_event(
+                "token_lock",
+                json!({ "token_ids": [grandchild_token_id.to_string()], "unlock_at": unlock_at }),
+            );
+        }
+
+        pub fn get_grandchild_unlock_time(&self, grandchild_token_id: u64) -> Option<U64> {
+            self.token_unlock_times.get(&grandchild_token_id).map(U64)
+        }
+
+        // Returns whether the token cannot be transferred at `timestamp`, or now if not given
+        pub fn is_grandchild_token_locked(&self, grandchild_token_id: u64, timestamp: Option<U64>) -> bool {
+            let timestamp = timestamp.map_or_else(env::block_timestamp, |timestamp| timestamp.0);
+            self.token_unlock_times
+                .get(&grandchild_token_id)
+                .is_some_and(|unlock_at| timestamp < unlock_at)
+        }
+
+        fn internal_balance_at(&self, account_id: &AccountId, timestamp: u64) -> Balance {
+            let balance = self.balances.get(account_id).unwrap_or(0);
+            match self.balance_indices.get(account_id) {
+                Some(settled_index) if balance > 0 => {
+                    let index = self.time_rate.index_at(timestamp);
+                    if index == settled_index {
+                        return balance;
+                    }
+                    mul_div(balance, index, settled_index).expect("Balance overflow")
+                }
+                _ => balance,
+            }
+        }
+
+        // Applies the growth or decay since the balance was last settled, minting or burning
+        // the difference, and records the current time index for the account
+        fn internal_settle_balance(&mut self, account_id: &AccountId) {
+            let now = env::block_timestamp();
+            let balance = self.balances.get(account_id).unwrap_or(0);
+            let settled_balance = self.internal_balance_at(account_id, now);
+            if settled_balance > balance {
+                let minted = settled_balance - balance;
+                self.total_supply = self.total_supply.checked_add(minted).expect("Total supply overflow");
+                self.internal_record_ft(None, Some(account_id), minted, TransactionReason::Mint);
+            } else if settled_balance < balance {
+                let burned = balance - settled_balance;
+                self.total_supply -= burned;
+                self.internal_record_ft(Some(account_id), None, burned, TransactionReason::Burn);
+            }
+            if settled_balance == 0 {
+                self.balances.remove(account_id);
+            } else {
+                self.balances.insert(account_id, &settled_balance);
+            }
+            self.balance_indices.insert(account_id, &self.time_rate.index_at(now));
+        }
+    }
+
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    impl SCTS777 {
+        // Transfer history
+        // Every mint, transfer and burn of a grandchild token is recorded in the token's
+        // history, oldest first, keeping the last MAX_TOKEN_HISTORY_LEN records. The history
+        // outlives the token when it is burned.
+
+        pub fn get_token_history(&self, token_id: u64, from_index: Option<U128>, limit: Option<u64>) -> Vec<Transaction> {
+            self.token_history
+                .get(&token_id)
+                .map_or_else(Vec::new, |history| paginate(&history, from_index, limit))
+        }
+
+        pub fn get_token_history_length(&self, token_id: u64) -> U64 {
+            U64(self.token_history.get(&token_id).map_or(0, |history| history.len()))
+        }
+    }
+
+    #[cfg(feature = "scts777")]
+    #[near_bindgen]
+    impl SCTS777 {
+        // Upgrades
+        // upgrade deploys new code and calls its migrate in the same batch, so a failing
+        // migration reverts the deployment as well.
+
+        // Deploys the Wasm passed as the raw call input and migrates the state to it.
+        // Can only be called by the contract owner.
+        pub fn upgrade(&mut self) -> Promise {
+            self.assert_owner();
+            let code = env::input().expect("Expected the contract code as input");
+            log_scts777_event("upgrade", json!({ "from_version": read_state_version(), "code_hash": Base64VecU8(env::sha256(&code)) }));
+            Promise::new(env::current_account_id())
+                .deploy_contract(code)
+                .function_call(b"migrate".to_vec(), b"{}".to_vec(), NO_DEPOSIT, GAS_FOR_MIGRATE)
+        }
+
+        // Converts the stored state to the current version. Version 1 balances, allowances
+        // and token ownership carry over; its single-account approvals are dropped and the
+        // contract account becomes the owner. Version 1 tokens are indexed afterwards with
+        // migrate_token_owners and their parents with migrate_grandchild_tokens.
+        #[private]
+        #[init(ignore_state)]
+        pub fn migrate() -> Self {
+            let state = match VersionedSCTS777::read() {
+                VersionedSCTS777::V1(old) => {
+                    let mut state = Self::internal_new(old.name, old.symbol, old.total_supply, env::current_account_id());
+                    state.balances = old.balances;
+                    state.allowances = old.allowances;
+                    state.next_token_id = old.next_token_id;
+                    state.token_owners = old.token_owners;
+                    state.token_balances = old.token_balances;
+                    state
+                }
+                VersionedSCTS777::V2(_) => env::panic(b"The contract state is already up to date"),
+            };
+            log_scts777_event("migrate", json!({ "version": SCTS777_STATE_VERSION }));
+            state
+        }
+
+        pub fn get_state_version(&self) -> u16 {
+            read_state_version()
+        }
+
+        // Indexes the owned version 1 tokens with IDs from from_token_id up to limit tokens
+        // later, root tokens included, in the owner and enumeration indices. Burned and
+        // already indexed tokens are skipped, so the range can be walked in chunks and
+        // resubmitted. Requires the Admin role.
+        pub fn migrate_token_owners(&mut self, from_token_id: u64, limit: u64) -> u64 {
+            self.assert_role(Role::Admin);
+            let end = from_token_id.saturating_add(limit).min(self.next_token_id);
+            let mut indexed = 0;
+            for token_id in from_token_id..end {
+                if self.internal_index_v1_token(token_id) {
+                    indexed += 1;
+                }
+            }
+            log_scts777_event("migrate_token_owners", json!({ "from_token_id": from_token_id, "indexed": indexed }));
+            indexed
+        }
+
+        // Indexes version 1 grandchild tokens, given as parent token -> grandchild tokens,
+        // in the parent index, and in the owner and enumeration indices when
+        // migrate_token_owners has not reached them yet. Every link is checked against the
+        // version 1 map and linked tokens are skipped, so the links can be supplied in chunks
+        // and resubmitted. Requires the Admin role.
+        pub fn migrate_grandchild_tokens(&mut self, links: Vec<(u64, Vec<u64>)>) -> u64 {
+            self.assert_role(Role::Admin);
+            let v1_grandchild_tokens: LookupMap<u64, LookupMap<u64, u64>> =
+                LookupMap::new(V1_GRANDCHILD_TOKENS_PREFIX.to_vec());
+            let mut indexed = 0;
+            for (parent_token_id, grandchild_token_ids) in links {
+                let v1_children = v1_grandchild_tokens
+                    .get(&parent_token_id)
+                    .unwrap_or_else(|| env::panic(format!("Token {} has no version 1 grandchild tokens", parent_token_id).as_bytes()));
+                let mut grandchild_tokens = self
+                    .grandchild_tokens
+                    .get(&parent_token_id)
+                    .unwrap_or_else(|| UnorderedSet::new(StorageKey::GrandchildTokensPerParent { parent_token_id }));
+                for grandchild_token_id in grandchild_token_ids {
+                    assert!(
+                        v1_children.contains_key(&grandchild_token_id),
+                        "Token {} is not a version 1 grandchild token of {}",
+                        grandchild_token_id,
+                        parent_token_id
+                    );
+                    if grandchild_tokens.contains(&grandchild_token_id) {
+                        continue;
+                    }
+                    if !self.token_owners.contains_key(&grandchild_token_id) {
+                        continue; // burned in version 1
+                    }
+                    self.internal_index_v1_token(grandchild_token_id);
+                    grandchild_tokens.insert(&grandchild_token_id);
+                    let mut metadata = self.token_metadata.get(&grandchild_token_id).unwrap_or_default();
+                    metadata.parent_token_id = Some(parent_token_id);
+                    self.token_metadata.insert(&grandchild_token_id, &metadata);
+                    indexed += 1;
+                }
+                if !grandchild_tokens.is_empty() {
+                    self.grandchild_tokens.insert(&parent_token_id, &grandchild_tokens);
+                }
+            }
+            log_scts777_event("migrate_grandchild_tokens", json!({ "indexed": indexed }));
+            indexed
+        }
+
+        // Adds an owned version 1 token to the owner and enumeration indices, returning
+        // whether it was indexed now.
+        fn internal_index_v1_token(&mut self, token_id: u64) -> bool {
+            if self.grandchild_token_ids.contains(&token_id) {
+                return false;
+            }
+            let owner_id = match self.token_owners.get(&token_id) {
+                Some(owner_id) => owner_id,
+                None => return false,
+            };
+            self.grandchild_token_ids.insert(&token_id);
+            self.internal_add_token_to_owner(&owner_id, token_id);
+            if self.token_metadata.get(&token_id).is_none() {
+                self.token_metadata.insert(&token_id, &StoredTokenMetadata::default());
+            }
+            true
+        }
+    }
+
+#[cfg(all(test, feature = "self-fractalized", feature = "scts777"))]
+mod tests {
+    use super::*;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::{testing_env, IntoStorageKey, MockedBlockchain};
+    use std::convert::TryFrom;
+
+    const TOTAL_SUPPLY: Balance = 1_000_000;
+
+    fn context(predecessor_account_id: ValidAccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(token_account())
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn token_account() -> ValidAccountId {
+        ValidAccountId::try_from("token.near").unwrap()
+    }
+
+    // Runs the next call as the contract itself, with `result` as the outcome of the promise it resolves
+    fn resolve_with(result: PromiseResult) {
+        testing_env!(
+            context(token_account()).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result]
+        );
+    }
+
+    const STORAGE_DEPOSIT: Balance = 10_000_000_000_000_000_000_000_000;
+
+    // Deploys SCTS777 as accounts(0) and registers it for storage
+    fn new_scts777() -> SCTS777 {
+        testing_env!(context(accounts(0)).build());
+        let mut contract = SCTS777::new("Fractal".to_string(), "FRC".to_string(), TOTAL_SUPPLY);
+        register(&mut contract, accounts(0));
+        contract
+    }
+
+    fn register(contract: &mut SCTS777, account_id: ValidAccountId) {
+        testing_env!(context(account_id).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.storage_deposit(None, None);
+        testing_env!(context(accounts(0)).build());
+    }
+
+    fn new_fractalized() -> SelfFractalizedSCTS777 {
+        testing_env!(context(accounts(0)).build());
+        SelfFractalizedSCTS777::new("Fractal".to_string(), "FRC".to_string(), U128(TOTAL_SUPPLY))
+    }
+
+    #[test]
+    fn fractalized_transfer_moves_balance() {
+        let mut contract = new_fractalized();
+        assert_eq!(contract.balance_of(accounts(0)).0, TOTAL_SUPPLY);
+
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        assert!(contract.transfer(accounts(1), U128(400)));
+        assert_eq!(contract.balance_of(accounts(0)).0, TOTAL_SUPPLY - 400);
+        assert_eq!(contract.balance_of(accounts(1)).0, 400);
+    }
+
+    #[test]
+    #[should_panic(expected = "Balance not enough")]
+    fn fractalized_transfer_rejects_overdraft() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.transfer(accounts(2), U128(1));
+    }
+
+    #[test]
+    fn fractalized_allowance_is_consumed_by_transfer_from() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        assert!(contract.approve(accounts(1), U128(300)));
+        assert_eq!(contract.allowance(accounts(0), accounts(1)).0, 300);
+        assert!(get_logs().last().unwrap().contains("\"event\":\"approval\""));
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        assert!(contract.transfer_from(accounts(0), accounts(2), U128(200)));
+        assert_eq!(contract.balance_of(accounts(2)).0, 200);
+        assert_eq!(contract.allowance(accounts(0), accounts(1)).0, 100);
+
+        testing_env!(context(accounts(0)).build());
+        contract.increase_allowance(accounts(1), U128(50));
+        contract.decrease_allowance(accounts(1), U128(150));
+        assert_eq!(contract.allowance(accounts(0), accounts(1)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Allowance not enough")]
+    fn fractalized_transfer_from_rejects_excess() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.approve(accounts(1), U128(10));
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.transfer_from(accounts(0), accounts(2), U128(11));
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct CreatedReceipt {
+        receiver_id: AccountId,
+        actions: Vec<CreatedAction>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    enum CreatedAction {
+        Transfer { deposit: Balance },
+    }
+
+    // Returns the receiver and deposit of each receipt the contract created, which must all
+    // be plain transfers
+    fn created_transfers() -> Vec<(AccountId, Balance)> {
+        near_sdk::test_utils::get_created_receipts()
+            .iter()
+            .map(|receipt| {
+                let json = near_sdk::serde_json::to_string(receipt).unwrap();
+                let receipt: CreatedReceipt = near_sdk::serde_json::from_str(&json).unwrap();
+                let CreatedAction::Transfer { deposit } = receipt.actions[0];
+                (receipt.receiver_id, deposit)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn allowance_storage_is_paid_by_the_deposit_and_refunded_when_freed() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.approve(accounts(1), U128(300));
+        // The owner's allowance map is created with its first allowance and then kept
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.approve(accounts(3), U128(300));
+        let refunded = created_transfers();
+        assert_eq!(refunded.len(), 1);
+        assert_eq!(refunded[0].0, AccountId::from(accounts(0)));
+        let storage_cost = STORAGE_DEPOSIT - refunded[0].1;
+        assert!(storage_cost > 0);
+
+        testing_env!(context(accounts(3)).attached_deposit(1).build());
+        contract.transfer_from(accounts(0), accounts(2), U128(300));
+        assert_eq!(created_transfers(), vec![(AccountId::from(accounts(0)), storage_cost)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "to cover the allowance storage")]
+    fn approve_requires_a_storage_deposit() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.approve(accounts(1), U128(300));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn fractalized_transfer_requires_one_yocto() {
+        let mut contract = new_fractalized();
+        contract.transfer(accounts(1), U128(400));
+    }
+
+    #[test]
+    fn ft_transfer_requires_one_yocto_and_logs_event() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(250), Some("rent".to_string()));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 250);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
+        assert!(get_logs().last().unwrap().contains("\"event\":\"ft_transfer\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn ft_transfer_rejects_missing_deposit() {
+        let mut contract = new_fractalized();
+        contract.ft_transfer(accounts(1), U128(250), None);
+    }
+
+    #[test]
+    fn ft_metadata_reports_nep148_fields() {
+        let contract = new_fractalized();
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.spec, FT_METADATA_SPEC);
+        assert_eq!(metadata.symbol, "FRC");
+        assert_eq!(metadata.decimals, FT_DECIMALS);
+    }
+
+    #[test]
+    fn ft_transfer_call_debits_sender_before_the_callback() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0))
+            .attached_deposit(1)
+            .prepaid_gas(2 * GAS_FOR_FT_TRANSFER_CALL)
+            .build());
+        contract.ft_transfer_call(accounts(1), U128(500), None, "deposit".to_string());
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 500);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 500);
+    }
+
+    #[test]
+    fn ft_resolve_transfer_refunds_unused_amount() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(500), None);
+
+        resolve_with(PromiseResult::Successful(b"\"150\"".to_vec()));
+        let used = contract.ft_resolve_transfer(accounts(0).into(), accounts(1).into(), U128(500));
+        assert_eq!(used.0, 350);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 350);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 350);
+    }
+
+    #[test]
+    fn ft_resolve_transfer_refunds_everything_when_the_receiver_fails() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(500), None);
+
+        resolve_with(PromiseResult::Failed);
+        let used = contract.ft_resolve_transfer(accounts(0).into(), accounts(1).into(), U128(500));
+        assert_eq!(used.0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn ft_resolve_transfer_refunds_no_more_than_the_receiver_holds() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(500), None);
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(2), U128(400), None);
+
+        resolve_with(PromiseResult::Successful(b"\"500\"".to_vec()));
+        let used = contract.ft_resolve_transfer(accounts(0).into(), accounts(1).into(), U128(500));
+        assert_eq!(used.0, 400);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 400);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn ft_resolve_transfer_refunds_the_decayed_balance() {
+        let mut contract = new_scts777();
+        contract.set_time_rate(-5_000, U64(1_000));
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(500), None);
+
+        // The receiver's balance has decayed by the time the transfer is resolved
+        testing_env!(context(accounts(0)).block_timestamp(1_000).build());
+        let decayed = contract.ft_balance_of(accounts(1)).0;
+        assert!(decayed < 500);
+        testing_env!(
+            context(token_account()).block_timestamp(1_000).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(b"\"500\"".to_vec())]
+        );
+        let used = contract.ft_resolve_transfer(accounts(0).into(), accounts(1).into(), U128(500));
+        assert_eq!(used.0, 500 - decayed);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn root_tokens_can_parent_grandchild_tokens() {
+        let mut contract = new_scts777();
+        register(&mut contract, accounts(1));
+        register(&mut contract, accounts(2));
+        let root_token_id = contract.mint_root_token(accounts(1));
+        assert_eq!(contract.nft_token(root_token_id.to_string()).unwrap().owner_id, AccountId::from(accounts(1)));
+
+        contract.generate_grandchild_token(root_token_id, accounts(2).into());
+        let grandchild_token_ids = contract.get_child_tokens(root_token_id, None, None);
+        assert_eq!(grandchild_token_ids.len(), 1);
+        assert_eq!(contract.nft_total_supply().0, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "Parent token does not exist")]
+    fn grandchild_tokens_need_an_existing_parent() {
+        let mut contract = new_scts777();
+        contract.generate_grandchild_token(7, accounts(2).into());
+    }
+
+    #[test]
+    #[should_panic(expected = "This function requires the Minter role")]
+    fn root_tokens_require_the_minter_role() {
+        let mut contract = new_scts777();
+        register(&mut contract, accounts(1));
+        testing_env!(context(accounts(1)).build());
+        contract.mint_root_token(accounts(1));
+    }
+
+    #[test]
+    fn burning_releases_storage_to_the_accounts_that_paid_for_it() {
+        let mut contract = new_scts777();
+        contract.deactivate(Feature::History);
+        register(&mut contract, accounts(1));
+        let root_token_id = contract.mint_root_token(accounts(0));
+        let minter_available = contract.storage_balance_of(accounts(0)).unwrap().available.0;
+        let owner_available = contract.storage_balance_of(accounts(1)).unwrap().available.0;
+
+        contract.generate_grandchild_token(root_token_id, accounts(1).into());
+        let grandchild_token_id = contract.get_tokens_for_owner(accounts(1), None, None)[0];
+        assert!(contract.storage_balance_of(accounts(0)).unwrap().available.0 < minter_available);
+        // The owner's balance map and token set are covered by its registration
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().available.0, owner_available);
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.nft_approve(grandchild_token_id.to_string(), accounts(2), None);
+        contract.patch_token_metadata(
+            grandchild_token_id,
+            TokenMetadataPatch { title: Some("Fractal".to_string()), ..Default::default() },
+        );
+        assert!(contract.storage_balance_of(accounts(1)).unwrap().available.0 < owner_available);
+
+        contract.burn_grandchild_token(root_token_id, grandchild_token_id);
+        assert_eq!(contract.storage_balance_of(accounts(0)).unwrap().available.0, minter_available);
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().available.0, owner_available);
+        assert!(contract.get_tokens_for_owner(accounts(1), None, None).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "The receiver is not registered")]
+    fn tokens_can_only_be_minted_to_registered_accounts() {
+        let mut contract = new_scts777();
+        contract.mint_root_token(accounts(1));
+    }
+
+    #[test]
+    fn transfers_leave_no_per_owner_collections_behind() {
+        let mut contract = new_scts777();
+        contract.deactivate(Feature::History);
+        register(&mut contract, accounts(1));
+        let token_id = contract.mint_root_token(accounts(0)).to_string();
+        let storage_usage = env::storage_usage();
+
+        // Each call continues from the storage usage the previous one left
+        testing_env!(context(accounts(0)).storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.nft_transfer(accounts(1), token_id.clone(), None, None);
+        assert_eq!(contract.nft_supply_for_owner(accounts(0)).0, 0);
+        testing_env!(context(accounts(1)).storage_usage(env::storage_usage()).attached_deposit(1).build());
+        contract.nft_transfer(accounts(0), token_id, None, None);
+        assert_eq!(env::storage_usage(), storage_usage);
+    }
+
+    // Mints a root token to accounts(1), with accounts(2) and accounts(3) registered as well
+    fn new_scts777_with_token() -> (SCTS777, u64) {
+        let mut contract = new_scts777();
+        for account_id in [accounts(1), accounts(2), accounts(3)] {
+            register(&mut contract, account_id);
+        }
+        let token_id = contract.mint_root_token(accounts(1));
+        (contract, token_id)
+    }
+
+    #[test]
+    #[should_panic(expected = "Sender not approved")]
+    fn grandchild_transfers_reject_accounts_that_are_not_approved() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(3)).attached_deposit(1).build());
+        contract.transfer_grandchild_tokens(accounts(1).into(), accounts(2), vec![token_id]);
+    }
+
+    #[test]
+    fn approved_spenders_can_transfer_grandchild_tokens_once() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        let approval_id = contract.approve_grandchild_token(token_id, accounts(3));
+
+        testing_env!(context(accounts(3)).attached_deposit(1).build());
+        assert!(contract.transfer_from_grandchild_token(accounts(1).into(), accounts(2), token_id, Some(approval_id)));
+        assert_eq!(contract.nft_token(token_id.to_string()).unwrap().owner_id, AccountId::from(accounts(2)));
+        assert!(!contract.nft_is_approved(token_id.to_string(), accounts(3), None));
+    }
+
+    #[test]
+    #[should_panic(expected = "Sender not approved")]
+    fn grandchild_transfers_use_up_the_approval() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.approve_grandchild_token(token_id, accounts(3));
+        testing_env!(context(accounts(3)).attached_deposit(1).build());
+        contract.transfer_from_grandchild_token(accounts(1).into(), accounts(2), token_id, None);
+
+        testing_env!(context(accounts(3)).attached_deposit(1).build());
+        contract.transfer_from_grandchild_token(accounts(2).into(), accounts(1), token_id, None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Grandchild token 99 does not exist")]
+    fn grandchild_transfers_reject_unknown_tokens() {
+        let (mut contract, _) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.transfer_grandchild_tokens(accounts(1).into(), accounts(2), vec![99]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Requires attached deposit of exactly 1 yoctoNEAR")]
+    fn grandchild_transfers_require_one_yocto() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).build());
+        contract.batch_transfer_grandchild_tokens(vec![(token_id, accounts(2))], None);
+    }
+
+    #[test]
+    #[should_panic(expected = "The receiver is not registered")]
+    fn tokens_can_only_be_transferred_to_registered_accounts() {
+        let mut contract = new_scts777();
+        let token_id = contract.mint_root_token(accounts(0)).to_string();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.nft_transfer(accounts(1), token_id, None, None);
+    }
+
+    #[test]
+    fn approval_ids_increase_and_replace_older_approvals() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        let first_id = contract.approve_grandchild_token(token_id, accounts(2));
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        let second_id = contract.approve_grandchild_token(token_id, accounts(3));
+        assert!(second_id > first_id);
+        assert!(contract.is_approved_grandchild_token(token_id, accounts(2), Some(first_id)));
+        assert!(!contract.is_approved_grandchild_token(token_id, accounts(2), Some(second_id)));
+        assert!(contract.is_approved_grandchild_token(token_id, accounts(3), Some(second_id)));
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        let renewed_id = contract.approve_grandchild_token(token_id, accounts(2));
+        assert!(renewed_id > second_id);
+        assert!(!contract.is_approved_grandchild_token(token_id, accounts(2), Some(first_id)));
+        assert!(contract.is_approved_grandchild_token(token_id, accounts(2), Some(renewed_id)));
+    }
+
+    #[test]
+    fn revoke_all_clears_every_approval() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        for account_id in [accounts(2), accounts(3)] {
+            testing_env!(context(accounts(1)).attached_deposit(1).build());
+            contract.approve_grandchild_token(token_id, account_id);
+        }
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.revoke_grandchild_token(token_id, accounts(2));
+        assert!(!contract.is_approved_grandchild_token(token_id, accounts(2), None));
+        assert!(contract.is_approved_grandchild_token(token_id, accounts(3), None));
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.revoke_all_grandchild_token(token_id);
+        assert!(!contract.is_approved_grandchild_token(token_id, accounts(3), None));
+        assert!(contract.nft_token(token_id.to_string()).unwrap().approved_account_ids.unwrap().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the token owner can change approvals")]
+    fn only_the_owner_can_approve() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(2)).attached_deposit(1).build());
+        contract.approve_grandchild_token(token_id, accounts(3));
+    }
+
+    #[test]
+    fn transfers_clear_every_approval() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        for account_id in [accounts(2), accounts(3)] {
+            testing_env!(context(accounts(1)).attached_deposit(1).build());
+            contract.approve_grandchild_token(token_id, account_id);
+        }
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.nft_transfer(accounts(2), token_id.to_string(), None, None);
+        assert!(!contract.is_approved_grandchild_token(token_id, accounts(2), None));
+        assert!(!contract.is_approved_grandchild_token(token_id, accounts(3), None));
+
+        // Approval IDs keep increasing for the new owner
+        testing_env!(context(accounts(2)).attached_deposit(1).build());
+        assert_eq!(contract.approve_grandchild_token(token_id, accounts(3)), 3);
+    }
+
+    #[test]
+    fn pausing_transfers_leaves_burns_available() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.generate_grandchild_token(token_id, accounts(2).into());
+        let grandchild_token_id = contract.get_tokens_for_owner(accounts(2), None, None)[0];
+
+        testing_env!(context(accounts(0)).block_timestamp(42).build());
+        contract.pause(Some(vec![PausableFunction::Transfer]), "Incident".to_string());
+        assert!(contract.is_paused(PausableFunction::Transfer));
+        assert!(!contract.is_paused(PausableFunction::Burn));
+        let info = &contract.get_paused_functions()[&PausableFunction::Transfer];
+        assert_eq!(info.reason, "Incident");
+        assert_eq!(info.paused_at.0, 42);
+        let event = get_logs().pop().unwrap();
+        assert!(event.contains("\"event\":\"pause\""));
+        assert!(event.contains("\"reason\":\"Incident\""));
+
+        testing_env!(context(accounts(2)).build());
+        contract.burn_grandchild_token(token_id, grandchild_token_id);
+        assert!(contract.nft_token(grandchild_token_id.to_string()).is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfer is paused: Incident")]
+    fn paused_transfers_fail_with_the_reason() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.pause(Some(vec![PausableFunction::Transfer]), "Incident".to_string());
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.nft_transfer(accounts(2), token_id.to_string(), None, None);
+    }
+
+    #[test]
+    fn unpausing_only_resumes_the_given_functions() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.pause(None, "Upgrade".to_string());
+        assert_eq!(contract.get_paused_functions().len(), 6);
+
+        contract.unpause(Some(vec![PausableFunction::Transfer]));
+        let logs = get_logs();
+        assert_eq!(logs.iter().filter(|log| log.contains("\"event\":\"unpause\"")).count(), 1);
+        assert!(contract.is_paused(PausableFunction::Mint));
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.nft_transfer(accounts(2), token_id.to_string(), None, None);
+        assert_eq!(contract.nft_token(token_id.to_string()).unwrap().owner_id, AccountId::from(accounts(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "This function requires the Pauser role")]
+    fn pausing_requires_the_pauser_role() {
+        let mut contract = new_scts777();
+        testing_env!(context(accounts(1)).build());
+        contract.pause(None, "Incident".to_string());
+    }
+
+    #[test]
+    fn granted_roles_last_until_revoked() {
+        let mut contract = new_scts777();
+        register(&mut contract, accounts(1));
+        contract.grant_role(Role::Minter, accounts(1));
+        assert!(contract.has_role(Role::Minter, accounts(1)));
+        assert_eq!(contract.get_role_members(Role::Minter), vec![AccountId::from(accounts(1))]);
+        assert!(get_logs().last().unwrap().contains("\"event\":\"role_granted\""));
+
+        testing_env!(context(accounts(1)).build());
+        let token_id = contract.mint_root_token(accounts(1));
+        assert_eq!(contract.nft_token(token_id.to_string()).unwrap().owner_id, AccountId::from(accounts(1)));
+
+        testing_env!(context(accounts(0)).build());
+        contract.revoke_role(Role::Minter, accounts(1));
+        assert!(!contract.has_role(Role::Minter, accounts(1)));
+        assert!(contract.get_role_members(Role::Minter).is_empty());
+        assert!(get_logs().last().unwrap().contains("\"event\":\"role_revoked\""));
+    }
+
+    #[test]
+    #[should_panic(expected = "This function requires the Minter role")]
+    fn renounced_roles_no_longer_apply() {
+        let mut contract = new_scts777();
+        register(&mut contract, accounts(1));
+        contract.grant_role(Role::Minter, accounts(1));
+        testing_env!(context(accounts(1)).build());
+        contract.renounce_role(Role::Minter);
+        assert!(!contract.has_role(Role::Minter, accounts(1)));
+        contract.mint_root_token(accounts(1));
+    }
+
+    #[test]
+    fn admins_manage_every_role_but_admin() {
+        let mut contract = new_scts777();
+        contract.grant_role(Role::Admin, accounts(1));
+        testing_env!(context(accounts(1)).build());
+        contract.grant_role(Role::Pauser, accounts(2));
+        assert!(contract.has_role(Role::Pauser, accounts(2)));
+        contract.revoke_role(Role::Pauser, accounts(2));
+        assert!(!contract.has_role(Role::Pauser, accounts(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "This function can only be called by the contract owner")]
+    fn only_the_owner_grants_the_admin_role() {
+        let mut contract = new_scts777();
+        contract.grant_role(Role::Admin, accounts(1));
+        testing_env!(context(accounts(1)).build());
+        contract.grant_role(Role::Admin, accounts(2));
+    }
+
+    #[test]
+    fn ownership_moves_once_the_pending_owner_accepts() {
+        let mut contract = new_scts777();
+        contract.transfer_ownership(Some(accounts(1)));
+        assert_eq!(contract.get_owner(), AccountId::from(accounts(0)));
+        assert_eq!(contract.get_pending_owner(), Some(AccountId::from(accounts(1))));
 
-impl SelfFractalizedSCTS777 {
-    // Constructor
-    #[init]
-    pub fn new(name: String, symbol: String, total_supply: Balance) -> Self {
-        let mut instance = Self {
-            name,
-            symbol,
-            total_supply,
-            balances: LookupMap::new(b"b".to_vec()),
-            allowances: LookupMap::new(b"a".to_vec()),
-            child_tokens: LookupMap::new(b"c".to_vec()),
-            parent_token: None,
-            parent_balance: None,
-        };
-        instance.balances.insert(&env::predecessor_account_id(), &total_supply);
-        instance
+        testing_env!(context(accounts(1)).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner(), AccountId::from(accounts(1)));
+        assert_eq!(contract.get_pending_owner(), None);
+        assert!(get_logs().last().unwrap().contains("\"event\":\"ownership_transferred\""));
+
+        // The owner holds every role implicitly
+        contract.pause(None, "Handover".to_string());
+        assert!(contract.is_paused(PausableFunction::Mint));
     }
 
-    // Standard functions
-    // ...
+    #[test]
+    #[should_panic(expected = "Only the pending owner can accept ownership")]
+    fn only_the_pending_owner_accepts_ownership() {
+        let mut contract = new_scts777();
+        contract.transfer_ownership(Some(accounts(1)));
+        testing_env!(context(accounts(2)).build());
+        contract.accept_ownership();
+    }
 
-    // Functions for self-fractalization
-    pub fn create_child_token(&mut self, name: String, symbol: String, total_supply: Balance) -> Promise {
-        let child_token_id = self.child_tokens.len() as u64;
-        let child_token_contract_name = format!("{}_child_{}", env::current_account_id(), child_token_id);
+    #[test]
+    #[should_panic(expected = "This function requires the Pauser role")]
+    fn previous_owners_lose_their_privileges() {
+        let mut contract = new_scts777();
+        contract.transfer_ownership(Some(accounts(1)));
+        testing_env!(context(accounts(1)).build());
+        contract.accept_ownership();
+        testing_env!(context(accounts(0)).build());
+        contract.pause(None, "Incident".to_string());
+    }
 
-        let child_token_contract = SelfFractalizedSCTS777::new(name, symbol, total_supply);
-        let child_token_contract_bytes = borsh::BorshSerialize::try_to_vec(&child_token_contract).unwrap();
-        let child_token_deposit = env::account_balance() / 10;
+    #[test]
+    fn features_can_be_toggled_and_listed() {
+        let mut contract = new_scts777();
+        assert!(contract.list_features().values().all(|active| *active));
+        assert_eq!(contract.list_features().len(), 5);
 
-        let promise = Promise::new(child_token_contract_name.clone())
-            .create_account()
-            .deploy_contract(child_token_contract_bytes)
-            .transfer(child_token_deposit)
-            .function_call(
-                b"new".to_vec(),
-                borsh::BorshSerialize::try_to_vec(&(name, symbol, total_supply)).unwrap(),
-                0,
-                env::prepaid_gas() - 25_000,
-            )
-            .then(ext_self_fractalized_scts777::register_child_token(
-                child_token_contract_name,
-                child_token_id,
-            ));
+        assert!(contract.deactivate(Feature::GrandchildMinting));
+        assert!(get_logs().last().unwrap().contains("\"event\":\"feature_deactivated\""));
+        assert!(!contract.deactivate(Feature::GrandchildMinting));
+        assert!(!contract.is_feature_active(Feature::GrandchildMinting));
+        assert!(!contract.list_features()[&Feature::GrandchildMinting]);
 
-        self.child_tokens.insert(&child_token_contract_name, &child_token_id);
-        promise
+        assert!(contract.activate(Feature::GrandchildMinting));
+        assert!(get_logs().last().unwrap().contains("\"event\":\"feature_activated\""));
+        assert!(contract.is_feature_active(Feature::GrandchildMinting));
     }
 
-    pub fn set_parent_token(&mut self, parent_token: String) -> Promise {
-        assert!(
-            self.parent_token.is_none(),
-            "Parent token already set"
-        );
-        assert!(
-            !self.child_tokens.contains_key(&parent_token),
-            "Cannot set parent token to a child token"
-        );
+    #[test]
+    #[should_panic(expected = "Feature GrandchildMinting is not active")]
+    fn inactive_grandchild_minting_blocks_mints() {
+        let mut contract = new_scts777();
+        contract.deactivate(Feature::GrandchildMinting);
+        contract.mint_root_token(accounts(0));
+    }
 
-        let parent_balance = self.balances.get(&parent_token).unwrap_or(&0);
-        self.balances.remove(&parent_token);
+    #[test]
+    #[should_panic(expected = "Feature Metadata is not active")]
+    fn inactive_metadata_blocks_metadata_updates() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.deactivate(Feature::Metadata);
+        testing_env!(context(accounts(1)).build());
+        contract.patch_token_metadata(token_id, TokenMetadataPatch { title: Some("Fractal".to_string()), ..Default::default() });
+    }
 
-        self.parent_token = Some(parent_token);
-        self.parent_balance = Some(parent_balance);
+    #[test]
+    fn inactive_history_records_nothing() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        let length = contract.get_token_history_length(token_id).0;
+        contract.deactivate(Feature::History);
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        contract.nft_transfer(accounts(2), token_id.to_string(), None, None);
+        assert_eq!(contract.get_token_history_length(token_id).0, length);
+    }
 
-        Promise::new(env::current_account_id()).as_return()
+    #[test]
+    #[should_panic(expected = "This function requires the Admin role")]
+    fn toggling_features_requires_the_admin_role() {
+        let mut contract = new_scts777();
+        testing_env!(context(accounts(1)).build());
+        contract.deactivate(Feature::History);
     }
 
-    pub fn remove_parent_token(&mut self) -> Promise {
-        assert!(
-            self.parent_token.is_some(),
-            "Parent token not set"
+    fn attributes(pairs: &[(&str, Option<&str>)]) -> Option<HashMap<String, Option<String>>> {
+        Some(pairs.iter().map(|(key, value)| (key.to_string(), value.map(str::to_string))).collect())
+    }
+
+    #[test]
+    fn patches_change_only_the_given_fields() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).build());
+        contract.set_token_metadata(
+            token_id,
+            TokenMetadataPatch {
+                title: Some("Fractal".to_string()),
+                description: Some("A fractal".to_string()),
+                attributes: attributes(&[("color", Some("red")), ("size", Some("big"))]),
+                ..Default::default()
+            },
         );
-    
-        let parent_token = self.parent_token.take().unwrap();
-        let parent_balance = self.balances.get(&parent_token.owner_id).unwrap();
-        let mut new_parent_balance = parent_balance - self.parent_token_quantity;
-        assert!(
-            new_parent_balance >= 0,
-            "Removing parent token would result in negative balance"
+
+        testing_env!(context(accounts(1)).block_timestamp(20).build());
+        contract.patch_token_metadata(
+            token_id,
+            TokenMetadataPatch {
+                description: Some(String::new()),
+                attributes: attributes(&[("size", None), ("shape", Some("round"))]),
+                ..Default::default()
+            },
         );
-    
-        if new_parent_balance == 0 {
-            self.balances.remove(&parent_token.owner_id);
-        } else {
-            self.balances.insert(&parent_token.owner_id, &new_parent_balance);
-        }
-    
-        self.total_supply -= self.parent_token_quantity;
-    
-        Promise::new(env::current_account_id()).as_return()
-    }
-    
-    pub fn get_child_tokens(&self, parent_token_id: u64) -> Option<Vec<u64>> {
-        let mut child_tokens = vec![];
-    
-        for (token_id, token) in self.tokens.iter() {
-            if let Some(pt) = token.parent_token {
-                if pt == parent_token_id {
-                    child_tokens.push(token_id);
-                }
-            }
-        }
-    
-        if child_tokens.is_empty() {
-            None
-        } else {
-            Some(child_tokens)
-        }
+        let metadata = contract.get_token_metadata(token_id).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Fractal"));
+        assert_eq!(metadata.description, None);
+        assert_eq!(metadata.attributes.len(), 2);
+        assert_eq!(metadata.attributes["color"], "red");
+        assert_eq!(metadata.attributes["shape"], "round");
+        // The token was minted at time 0
+        assert_eq!(metadata.created_at.0, 0);
+        assert_eq!(metadata.updated_at.0, 20);
+        assert!(get_logs().last().unwrap().contains("\"event\":\"token_metadata_update\""));
+
+        // Setting replaces every editable field
+        contract.set_token_metadata(token_id, TokenMetadataPatch { description: Some("New".to_string()), ..Default::default() });
+        let metadata = contract.get_token_metadata(token_id).unwrap();
+        assert_eq!(metadata.title, None);
+        assert_eq!(metadata.description.as_deref(), Some("New"));
+        assert!(metadata.attributes.is_empty());
+        assert_eq!(metadata.created_at.0, 0);
     }
-    
-    pub fn add_child_token(&mut self, parent_token_id: u64, child_token_id: u64) -> Promise {
-        assert!(
-            self.token_owners.contains_key(&parent_token_id),
-            "Parent token does not exist"
-        );
-    
-        let owner = self.token_owners.get(&parent_token_id).unwrap();
-        assert_eq!(
-            owner,
-            &env::predecessor_account_id(),
-            "Only the token owner can add child tokens"
+
+    #[test]
+    fn grandchild_tokens_inherit_unset_metadata_from_their_parent() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.generate_grandchild_token(token_id, accounts(2).into());
+        let grandchild_token_id = contract.get_tokens_for_owner(accounts(2), None, None)[0];
+
+        testing_env!(context(accounts(1)).build());
+        contract.patch_token_metadata(
+            token_id,
+            TokenMetadataPatch {
+                title: Some("Root".to_string()),
+                media: Some("ipfs://root".to_string()),
+                media_hash: Some(Base64VecU8(vec![1; METADATA_MEDIA_HASH_LEN])),
+                attributes: attributes(&[("color", Some("red")), ("size", Some("big"))]),
+                ..Default::default()
+            },
         );
-    
-        let child_owners = self
-            .token_child_owners
-            .get(&parent_token_id)
-            .unwrap_or(&LookupMap::new(format!("Child owners of token {}", parent_token_id).as_bytes().to_vec()));
-    
-        assert!(
-            !child_owners.contains_key(&child_token_id),
-            "Child token already exists"
+        testing_env!(context(accounts(2)).build());
+        contract.patch_token_metadata(
+            grandchild_token_id,
+            TokenMetadataPatch {
+                title: Some("Grandchild".to_string()),
+                attributes: attributes(&[("size", Some("small"))]),
+                ..Default::default()
+            },
         );
-    
-        child_owners.insert(&child_token_id, &env::predecessor_account_id());
-        self.token_child_owners
-            .insert(&parent_token_id, &child_owners);
-    
-        Promise::new(env::current_account_id()).as_return()
-    }
-    use near_sdk::{env, Promise, StorageMap, BorshStorageKey};
-use crate::token::{Token, TokenId};
-use crate::parent_token::ParentToken;
 
-#[derive(BorshStorageKey, BorshSerialize)]
-pub enum ChildTokenKey {
-    #[key("child_token")]
-    ChildToken(TokenId),
-    #[key("parent_token")]
-    ParentToken(TokenId),
-}
+        let metadata = contract.get_token_metadata(grandchild_token_id).unwrap();
+        assert_eq!(metadata.parent_token_id, Some(token_id));
+        assert_eq!(metadata.title.as_deref(), Some("Grandchild"));
+        assert_eq!(metadata.media.as_deref(), Some("ipfs://root"));
+        assert_eq!(metadata.media_hash.unwrap().0, vec![1; METADATA_MEDIA_HASH_LEN]);
+        assert_eq!(metadata.attributes["color"], "red");
+        assert_eq!(metadata.attributes["size"], "small");
 
-impl ParentToken {
-    pub fn transfer_child_tokens(&mut self, recipient: TokenId, token_ids: Vec<TokenId>) -> Promise {
-        let sender_id = env::predecessor_account_id();
-        let mut sender_token = self.get_child_token(&sender_id)
-            .expect("Sender does not own any tokens");
-        let mut recipient_token = self.get_child_token(&recipient)
-            .expect("Recipient does not own any tokens");
+        // Clearing the title inherits it again
+        contract.patch_token_metadata(grandchild_token_id, TokenMetadataPatch { title: Some(String::new()), ..Default::default() });
+        assert_eq!(contract.get_token_metadata(grandchild_token_id).unwrap().title.as_deref(), Some("Root"));
+    }
 
-        for token_id in token_ids.iter() {
-            let balance = sender_token.get_balance(token_id)
-                .expect("Token not found in sender's balance");
-            sender_token.remove_balance(token_id, balance);
-            recipient_token.add_balance(token_id, balance);
+    #[test]
+    fn metadata_managers_can_update_any_token() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.grant_role(Role::MetadataManager, accounts(3));
+        testing_env!(context(accounts(3)).build());
+        contract.patch_token_metadata(token_id, TokenMetadataPatch { title: Some("Curated".to_string()), ..Default::default() });
+        assert_eq!(contract.get_token_metadata(token_id).unwrap().title.as_deref(), Some("Curated"));
+    }
 
-            let key = ChildTokenKey::ChildToken(token_id.clone());
-            self.child_tokens.remove(&key);
-            let key = ChildTokenKey::ParentToken(token_id.clone());
-            self.child_tokens.insert(&key, &recipient);
-        }
+    #[test]
+    #[should_panic(expected = "This function requires the MetadataManager role")]
+    fn only_owners_and_metadata_managers_update_metadata() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(3)).build());
+        contract.patch_token_metadata(token_id, TokenMetadataPatch { title: Some("Mine".to_string()), ..Default::default() });
+    }
 
-        Promise::new(env::current_account_id()).as_return()
+    #[test]
+    #[should_panic(expected = "Title is too long")]
+    fn metadata_titles_are_limited() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).build());
+        let title = "a".repeat(MAX_METADATA_TITLE_LEN + 1);
+        contract.patch_token_metadata(token_id, TokenMetadataPatch { title: Some(title), ..Default::default() });
     }
-}
 
-    use near_sdk::{
-        borsh::{self, BorshDeserialize, BorshSerialize},
-        env, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
-    };
-    
-    #[near_bindgen]
-    #[derive(BorshSerialize, BorshDeserialize, PanicOnDefault)]
-    pub struct SCTS777 {
-        // Standard fields
-        name: String,
-        symbol: String,
-        total_supply: Balance,
-        balances: LookupMap<AccountId, Balance>,
-        allowances: LookupMap<AccountId, LookupMap<AccountId, Balance>>,
-    
-        // New fields for non-fungible tokens and granchild tokens
-        next_token_id: u64,
-        token_owners: LookupMap<u64, AccountId>,
-        token_balances: LookupMap<AccountId, LookupMap<u64, Balance>>,
-        token_approvals: LookupMap<u64, AccountId>,
-        granchild_tokens: LookupMap<u64, LookupMap<u64, u64>>,
+    #[test]
+    #[should_panic(expected = "Too many attributes")]
+    fn metadata_attributes_are_limited() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).build());
+        let attributes = (0..=MAX_METADATA_ATTRIBUTES).map(|index| (index.to_string(), Some("x".to_string()))).collect();
+        contract.patch_token_metadata(token_id, TokenMetadataPatch { attributes: Some(attributes), ..Default::default() });
     }
-    
-    impl SCTS777 {
-        // Constructor
-        #[init]
-        pub fn new(name: String, symbol: String, total_supply: Balance) -> Self {
-            let mut instance = Self {
-                name,
-                symbol,
-                total_supply,
-                balances: LookupMap::new(b"b".to_vec()),
-                allowances: LookupMap::new(b"a".to_vec()),
-                next_token_id: 0,
-                token_owners: LookupMap::new(b"o".to_vec()),
-                token_balances: LookupMap::new(b"t".to_vec()),
-                token_approvals: LookupMap::new(b"p".to_vec()),
-                grandchild_tokens: LookupMap::new(b"g".to_vec()),
-            };
-            instance.balances.insert(&env::predecessor_account_id(), &total_supply);
-            instance
-        }
-    
-        // Standard functions
-        // ...
 
-        // Functions for self-fractalization
-        // ...
+    #[test]
+    #[should_panic(expected = "Media hash requires a media URI")]
+    fn media_hashes_need_a_media_uri() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(1)).build());
+        contract.patch_token_metadata(
+            token_id,
+            TokenMetadataPatch { media_hash: Some(Base64VecU8(vec![1; METADATA_MEDIA_HASH_LEN])), ..Default::default() },
+        );
+    }
 
-        // Functions for funible tokens
-        // ...
-    
-        // Functions for non-fungible tokens
-        // ...
-    
-        // Functions for grandchild tokens
-        pub fn generate_grandchild_token(&mut self, parent_token_id: u64, to: AccountId) -> Promise {
-            assert!(
-                self.token_owners.contains_key(&parent_token_id),
-                "Parent token does not exist"
-            );
-    
-            let grandchild_token_id = self.next_token_id;
-            self.next_token_id += 1;
-    
-            self.token_owners.insert(&grandchild_token_id, &to);
-    
-            let grandchild_token_balance = self.token_balances
-                .get(&to)
-                .unwrap_or(&LookupMap::new(format!("Token balances of {}", to).as_bytes().to_vec()));
-            grandchild_token_balance.insert(&grandchild_token_id, &1);
-            self.token_balances.insert(&to, &grandchild_token_balance);
-    
-            let grandchild_tokens = self.grandchild_tokens
-                .get(&parent_token_id)
-                .unwrap_or(&LookupMap::new(format!("Grandchild tokens of parent token {}", parent_token_id).as_bytes().to_vec()));
-            grandchild_tokens.insert(&grandchild_token_id, &1);
-            self.grandchild_tokens.insert(&parent_token_id, &grandchild_tokens);
-    
-            Promise::new(env::current_account_id()).as_return()
-        }
+    fn batch_token_ids(batch: &BatchResult) -> Vec<Result<Vec<String>, String>> {
+        batch
+            .results
+            .iter()
+            .map(|result| match result {
+                BatchItemResult::Ok { token_ids } => Ok(token_ids.clone()),
+                BatchItemResult::Failed { reason } => Err(reason.clone()),
+            })
+            .collect()
     }
-    
-    pub fn transfer_grandchild_tokens(&mut self, from: AccountId, to: AccountId, grandchild_token_ids: Vec<u64>) -> Promise {
-        let mut sender_grandchild_token_balances = self.token_balances.get(&from).unwrap();
-        let mut recipient_grandchild_token_balances = self.token_balances.get(&to).unwrap_or(&LookupMap::new(to.as_bytes().to_vec()));
-    
-        for grandchild_token_id in grandchild_token_ids.iter() {
-            let balance = sender_grandchild_token_balances.get(grandchild_token_id).unwrap_or(&0);
-            sender_grandchild_token_balances.remove(grandchild_token_id);
-    
-            recipient_grandchild_token_balances.insert(grandchild_token_id, balance);
-            self.token_owners.insert(grandchild_token_id, &to);
-        }
-    
-        self.token_balances.insert(&from, &sender_grandchild_token_balances);
-        self.token_balances.insert(&to, &recipient_grandchild_token_balances);
-    
-        Promise::new(env::current_account_id()).as_return()
+
+    fn count_events(event: &str) -> usize {
+        get_logs().iter().filter(|log| log.contains(&format!("\"event\":\"{}\"", event))).count()
     }
 
-    pub fn burn_grandchild_token(&mut self, parent_token_id: u64, grandchild_token_id: u64) -> Promise {
-        let owner_id = env::predecessor_account_id();
-        assert!(
-            self.granchild_tokens.contains_key(&parent_token_id),
-            "Parent token does not exist"
+    #[test]
+    fn batch_mints_report_each_item() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        testing_env!(context(accounts(0)).build());
+        let batch = contract.batch_generate_grandchild_tokens(
+            token_id,
+            vec![
+                (accounts(2).into(), 2),
+                (accounts(4).into(), 1),
+                (accounts(3).into(), 0),
+                ("Invalid!".to_string(), 1),
+                (accounts(3).into(), 1),
+            ],
         );
-        let grandchild_tokens = self.granchild_tokens.get(&parent_token_id).unwrap();
-        assert!(
-            grandchild_tokens.contains_key(&grandchild_token_id),
-            "Grandchild token does not exist"
+        assert_eq!(
+            batch_token_ids(&batch),
+            vec![
+                Ok(vec!["1".to_string(), "2".to_string()]),
+                Err("Account is not registered".to_string()),
+                Err(format!("Count must be between 1 and {}", MAX_BATCH_MINT_COUNT)),
+                Err("Invalid account ID".to_string()),
+                Ok(vec!["3".to_string()]),
+            ]
         );
-        let grandchild_owner_id = self.token_owners.get(&grandchild_token_id).unwrap();
+        assert_eq!(batch.next_index, None);
+        assert_eq!(contract.get_child_tokens(token_id, None, None), vec![1, 2, 3]);
+        assert_eq!(count_events("nft_mint"), 1);
+    }
+
+    #[test]
+    fn batch_burns_report_each_item() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.batch_generate_grandchild_tokens(token_id, vec![(accounts(2).into(), 2), (accounts(3).into(), 1)]);
+
+        testing_env!(context(accounts(2)).build());
+        let batch = contract.batch_burn_grandchild_tokens(token_id, vec![1, 3, 99, 1, 2]);
         assert_eq!(
-            grandchild_owner_id,
-            &owner_id,
-            "Only the grandchild token owner can burn the token"
+            batch_token_ids(&batch),
+            vec![
+                Ok(vec!["1".to_string()]),
+                Err("Only the grandchild token owner can burn the token".to_string()),
+                Err("Grandchild token does not exist".to_string()),
+                Err("Grandchild token does not exist".to_string()),
+                Ok(vec!["2".to_string()]),
+            ]
         );
-    
-        // Reduce the balance of the grandchild token owner
-        let grandchild_owner_balance = self.token_balances.get(&owner_id).unwrap();
-        let mut grandchild_balance = grandchild_owner_balance
-            .get(&grandchild_token_id)
-            .expect("Grandchild token balance not found");
-        grandchild_balance -= 1;
-        if grandchild_balance == 0 {
-            grandchild_owner_balance.remove(&grandchild_token_id);
-        } else {
-            grandchild_owner_balance.insert(&grandchild_token_id, &grandchild_balance);
+        assert_eq!(batch.next_index, None);
+        assert_eq!(contract.get_child_tokens(token_id, None, None), vec![3]);
+        assert_eq!(count_events("nft_burn"), 1);
+    }
+
+    #[test]
+    fn batch_transfers_report_each_item() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        contract.generate_grandchild_token(token_id, accounts(2).into());
+
+        testing_env!(context(accounts(1)).attached_deposit(1).build());
+        let batch = contract.batch_transfer_grandchild_tokens(vec![(token_id, accounts(3)), (1, accounts(3)), (99, accounts(3))], None);
+        let results = batch_token_ids(&batch);
+        assert_eq!(results[0], Ok(vec![token_id.to_string()]));
+        assert_eq!(results[1], Err("Sender not approved".to_string()));
+        assert!(results[2].is_err());
+        assert_eq!(batch.next_index, None);
+        assert_eq!(contract.get_tokens_for_owner(accounts(3), None, None), vec![token_id]);
+        assert_eq!(count_events("nft_transfer"), 1);
+    }
+
+    #[test]
+    fn batches_stop_before_running_out_of_gas() {
+        let (mut contract, token_id) = new_scts777_with_token();
+        // Enough gas for the first item and the finish, but not for the three tokens that follow
+        testing_env!(context(accounts(0))
+            .prepaid_gas(GAS_FOR_BATCH_FINISH + 3 * GAS_PER_BATCH_TOKEN)
+            .build());
+        let batch = contract.batch_generate_grandchild_tokens(
+            token_id,
+            vec![(accounts(2).into(), 1), (accounts(3).into(), 3), (accounts(2).into(), 1)],
+        );
+        assert_eq!(batch.results.len(), 1);
+        assert_eq!(batch.next_index, Some(1));
+        assert_eq!(contract.get_child_tokens(token_id, None, None), vec![1]);
+    }
+
+    // Prefixes of every current collection, with two nested collections per nested variant
+    fn collection_prefixes() -> Vec<Vec<u8>> {
+        let mut keys = vec![
+            StorageKey::Balances,
+            StorageKey::Allowances,
+            StorageKey::ChildTokens,
+            StorageKey::TokenOwners,
+            StorageKey::TokenBalances,
+            StorageKey::TokenApprovals,
+            StorageKey::NextApprovalId,
+            StorageKey::GrandchildTokens,
+            StorageKey::GrandchildTokenIds,
+            StorageKey::TokensPerOwner,
+            StorageKey::StorageBalances,
+            StorageKey::ChildCode,
+            StorageKey::LockedBalances,
+            StorageKey::RoleMembers,
+            StorageKey::TokenMetadata,
+            StorageKey::FtHistory,
+            StorageKey::TokenHistory,
+            StorageKey::BalanceIndices,
+            StorageKey::TokenUnlockTimes,
+            StorageKey::VestingSchedules,
+            StorageKey::ChildTokenAccounts,
+            StorageKey::TokenStoragePayers,
+            StorageKey::PendingChildTokens,
+            StorageKey::ChildTokensPerCreator,
+        ];
+        for account_hash in [vec![1u8; 32], vec![2u8; 32]] {
+            keys.push(StorageKey::AllowancesPerOwner { account_hash: account_hash.clone() });
+            keys.push(StorageKey::TokenBalancesPerOwner { account_hash: account_hash.clone() });
+            keys.push(StorageKey::TokensPerOwnerInner { account_hash: account_hash.clone() });
+            keys.push(StorageKey::ChildTokensPerCreatorInner { account_hash });
         }
-        self.token_balances.insert(&owner_id, &grandchild_owner_balance);
-    
-        // Remove the grandchild token from the granchild_tokens list
-        let mut new_grandchild_tokens = LookupMap::new(format!("Grandchild tokens of parent token {}", parent_token_id).as_bytes().to_vec());
-        for (token_id, _) in grandchild_tokens.iter() {
-            if token_id != &grandchild_token_id {
-                new_grandchild_tokens.insert(&token_id, &1);
-            }
+        for id in [1, 256] {
+            keys.push(StorageKey::GrandchildTokensPerParent { parent_token_id: id });
+            keys.push(StorageKey::TokenHistoryPerToken { token_id: id });
         }
-        self.granchild_tokens.insert(&parent_token_id, &new_grandchild_tokens);
-    
-        Promise::new(env::current_account_id()).as_return()
+        keys.push(StorageKey::RoleMembersPerRole { role: Role::Admin });
+        keys.push(StorageKey::RoleMembersPerRole { role: Role::Minter });
+        keys.into_iter().map(|key| key.into_storage_key()).collect()
     }
-    
-pub fn allowance_grandchild_token(&mut self, grandchild_token_id: u64, spender_id: AccountId, allowance: Balance) -> Promise {
-    let owner_id = env::predecessor_account_id();
-    assert!(
-        self.token_owners.contains_key(&grandchild_token_id),
-        "Grandchild token does not exist"
-    );
-    assert_eq!(
-        owner_id,
-        self.token_owners.get(&grandchild_token_id).unwrap(),
-        "Account does not own grandchild token"
-    );
 
-    let grandchild_token_allowances = self.token_allowances
-        .get(&owner_id)
-        .unwrap_or(&LookupMap::new(format!("Token allowances of {}", owner_id).as_bytes().to_vec()));
+    #[test]
+    fn collection_prefixes_do_not_overlap() {
+        let prefixes = collection_prefixes();
+        for (i, a) in prefixes.iter().enumerate() {
+            for (j, b) in prefixes.iter().enumerate() {
+                assert!(i == j || !b.starts_with(a), "prefix {:?} overlaps {:?}", a, b);
+            }
+        }
+    }
 
-    grandchild_token_allowances.insert(&spender_id, &allowance);
-    self.token_allowances.insert(&owner_id, &grandchild_token_allowances);
+    #[test]
+    fn collection_prefixes_do_not_overlap_version_1_state() {
+        // Collections of the version 1 layout that migrate carries over, and the raw state keys
+        let v1_prefixes: [&[u8]; 6] = [b"b", b"a", b"o", b"t", b"p", V1_GRANDCHILD_TOKENS_PREFIX];
+        let state_keys: [&[u8]; 2] = [b"STATE", STATE_VERSION_KEY];
+        for prefix in collection_prefixes() {
+            for v1_prefix in v1_prefixes.iter() {
+                assert!(
+                    !prefix.starts_with(v1_prefix) && !v1_prefix.starts_with(&prefix),
+                    "prefix {:?} overlaps version 1 prefix {:?}",
+                    prefix,
+                    v1_prefix
+                );
+            }
+            for state_key in state_keys.iter() {
+                assert!(!state_key.starts_with(&prefix[..1]), "prefix {:?} overlaps {:?}", prefix, state_key);
+            }
+        }
+    }
 
-    Promise::new(env::current_account_id()).as_return()
-}
+    #[test]
+    fn child_supply_goes_to_the_creator_who_can_redeem_it() {
+        let child_account_id = ValidAccountId::try_from("child.token.near").unwrap();
+        testing_env!(context(token_account()).current_account_id(child_account_id.clone()).build());
+        let mut child = SelfFractalizedSCTS777::new_child("Child".to_string(), "CHD".to_string(), U128(1_000), U128(1), accounts(1).into());
+        assert_eq!(child.get_owner(), AccountId::from(accounts(1)));
+        assert_eq!(child.ft_balance_of(accounts(1)).0, 1_000);
+        assert_eq!(child.ft_balance_of(token_account()).0, 0);
 
-#[allow(dead_code)]
-impl SelfFractalizedSCTS777 {
-// Standard functions
-// ...
+        // The parent burns the creator's fractions when it redeems them
+        let released = child.burn_for_redeem(accounts(1).into(), U128(400));
+        assert_eq!(released.0, 400);
+        assert_eq!(child.ft_balance_of(accounts(1)).0, 600);
+        assert_eq!(child.ft_total_supply().0, 600);
+    }
 
-// Functions for self-fractalization
-// ...
+    // Stores placeholder child code and starts creating "child.token.near" as accounts(0)
+    fn start_child_creation(contract: &mut SelfFractalizedSCTS777) -> AccountId {
+        let mut set_code = context(accounts(0)).build();
+        set_code.input = vec![0; 10];
+        testing_env!(set_code);
+        contract.set_child_code();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.create_child_token("child".to_string(), "Child".to_string(), "CHD".to_string(), U128(1_000), None);
+        "child.token.near".to_string()
+    }
 
-// Functions for grandchild tokens
-// ...
+    #[test]
+    #[should_panic(expected = "Feature SelfFractalization is not active")]
+    fn inactive_self_fractalization_blocks_child_creation() {
+        let mut contract = new_fractalized();
+        contract.deactivate(Feature::SelfFractalization);
+        start_child_creation(&mut contract);
+    }
 
-pub fn allowance_grandchild_token(&mut self, grandchild_token_id: u64, spender_id: AccountId, allowance: Balance) -> Promise {
-    let owner_id = env::predecessor_account_id();
-    assert!(
-        self.token_owners.contains_key(&grandchild_token_id),
-        "Grandchild token does not exist"
-    );
-    assert_eq!(
-        owner_id,
-        self.token_owners.get(&grandchild_token_id).unwrap(),
-        "Account does not own grandchild token"
-    );
+    #[test]
+    fn inactive_history_records_no_fungible_transfers() {
+        let mut contract = new_fractalized();
+        let length = contract.get_ft_history_length().0;
+        contract.deactivate(Feature::History);
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(10), None);
+        assert_eq!(contract.get_ft_history_length().0, length);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10);
+    }
 
-    let grandchild_token_allowances = self.token_allowances
-        .get(&owner_id)
-        .unwrap_or(&LookupMap::new(format!("Token allowances of {}", owner_id).as_bytes().to_vec()));
+    #[test]
+    fn child_tokens_are_listed_for_their_parent_and_creator() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(2_000), None);
+        let first = start_child_creation(&mut contract);
+        resolve_with(PromiseResult::Successful(vec![]));
+        contract.on_child_token_created(first.clone(), accounts(0).into(), U128(1_000), U128(STORAGE_DEPOSIT));
 
-    grandchild_token_allowances.insert(&spender_id, &allowance);
-    self.token_allowances.insert(&owner_id, &grandchild_token_allowances);
+        testing_env!(context(accounts(1)).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.create_child_token("second".to_string(), "Second".to_string(), "SND".to_string(), U128(2_000), Some(U128(2)));
+        let second = "second.token.near".to_string();
+        resolve_with(PromiseResult::Successful(vec![]));
+        contract.on_child_token_created(second.clone(), accounts(1).into(), U128(1_000), U128(STORAGE_DEPOSIT));
 
-    Promise::new(env::current_account_id()).as_return()
-}
+        let children = contract.get_child_tokens(token_account(), None, None);
+        let listed: Vec<(u64, AccountId, Balance)> = children
+            .into_iter()
+            .map(|child| (child.child_token_id, child.account_id, child.locked_balance.0))
+            .collect();
+        assert_eq!(listed, vec![(0, first.clone(), 1_000), (1, second.clone(), 1_000)]);
+        let page = contract.get_child_tokens(token_account(), Some(U128(1)), Some(1));
+        assert_eq!(page.len(), 1);
+        assert_eq!((page[0].child_token_id, page[0].account_id.clone()), (1, second.clone()));
+        // A child's own children are registered in the child contract
+        assert!(contract.get_child_tokens(ValidAccountId::try_from(first.clone()).unwrap(), None, None).is_empty());
 
-    pub fn approve_grandchild_token(&mut self, token_id: u64, spender: AccountId, amount: Balance) -> bool {
-        let owner = self.token_owners.get(&token_id).expect("Token does not exist");
-        assert_eq!(
-            owner,
-            env::predecessor_account_id(),
-            "Only token owner can approve a spender"
-        );
-        let mut approved_tokens = self.token_approvals
-            .get(&spender)
-            .unwrap_or(&LookupMap::new(format!("Approved tokens for spender {}", spender).as_bytes().to_vec()));
-        approved_tokens.insert(&token_id, &amount);
-        self.token_approvals.insert(&spender, &approved_tokens);
-        true
+        let created = contract.get_tokens_for_owner(accounts(1), None, None);
+        assert_eq!(created.len(), 1);
+        assert_eq!((created[0].child_token_id, created[0].account_id.clone()), (1, second));
+        assert_eq!(contract.get_tokens_for_owner(accounts(0), None, None)[0].account_id, first);
+        assert!(contract.get_tokens_for_owner(accounts(2), None, None).is_empty());
     }
-}
 
-pub fn transfer_from_grandchild_token(&mut self, from: AccountId, to: AccountId, grandchild_token_id: u64, amount: u64) -> bool {
-    let spender = env::predecessor_account_id();
-    let allowance = self.allowances.get(&(from.clone(), spender)).unwrap_or(0);
-    let owner = self.granchild_tokens.get(&grandchild_token_id).expect("Grandchild token does not exist");
-    let owner_account = owner.get(&1).expect("Owner account does not exist");
+    #[test]
+    fn failed_child_creation_returns_the_backing_and_frees_the_account() {
+        let mut contract = new_fractalized();
+        let child_account_id = start_child_creation(&mut contract);
+        assert_eq!(contract.get_locked_balance(child_account_id.clone()).0, 1_000);
+        assert!(get_logs()[0].contains("\"event\":\"lock\""));
 
-    assert!(allowance >= amount, "Allowance not enough");
-    assert!(owner_account == &from, "Sender is not the owner of the token");
-    
-    let grandchild_token_balance = self.token_balances
-        .get(&from)
-        .expect("Sender has no tokens");
-    let balance = grandchild_token_balance.get(&grandchild_token_id).expect("Token does not exist");
-    
-    assert!(balance >= amount, "Balance not enough");
-    
-    grandchild_token_balance.insert(&grandchild_token_id, &(balance - amount));
-    self.token_balances.insert(&from, &grandchild_token_balance);
-    
-    let recipient_token_balance = self.token_balances
-        .get(&to)
-        .unwrap_or(&LookupMap::new(format!("Token balances of {}", to).as_bytes().to_vec()));
-    recipient_token_balance.insert(&grandchild_token_id, &(recipient_token_balance.get(&grandchild_token_id).unwrap_or(0) + amount));
-    self.token_balances.insert(&to, &recipient_token_balance);
-    
-    if allowance > 0 {
-        self.allowances.insert(&(from.clone(), spender), &(allowance - amount));
+        resolve_with(PromiseResult::Failed);
+        assert!(!contract.on_child_token_created(
+            child_account_id.clone(),
+            accounts(0).into(),
+            U128(1_000),
+            U128(STORAGE_DEPOSIT)
+        ));
+        assert_eq!(contract.get_locked_balance(child_account_id.clone()).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY);
+        assert!(contract.get_tokens_for_owner(accounts(0), None, None).is_empty());
+        let logs = get_logs();
+        assert!(logs[0].contains("\"event\":\"release\""));
+        assert!(logs[1].contains("\"event\":\"child_token_create_failure\""));
+
+        // The account is no longer reserved
+        start_child_creation(&mut contract);
+        assert_eq!(contract.get_locked_balance(child_account_id).0, 1_000);
     }
-    
-    true
-}
 
-impl SCTS777 {
-    // ...
+    #[test]
+    #[should_panic(expected = "Child token is already being created")]
+    fn pending_child_accounts_are_reserved() {
+        let mut contract = new_fractalized();
+        start_child_creation(&mut contract);
+        start_child_creation(&mut contract);
+    }
+
+    #[test]
+    fn children_keep_the_ratio_chosen_at_creation() {
+        let child_account_id = ValidAccountId::try_from("child.token.near").unwrap();
+        testing_env!(context(token_account()).current_account_id(child_account_id).build());
+        let mut child =
+            SelfFractalizedSCTS777::new_child("Child".to_string(), "CHD".to_string(), U128(1_000), U128(100), accounts(1).into());
+        assert_eq!(child.ft_balance_of(accounts(1)).0, 1_000);
 
-    // Functions for grandchild tokens
-    // ...
+        // The parent fractionalizes more of the creator's tokens at the same ratio
+        child.mint_backed(accounts(1).into(), U128(5), U128(100));
+        assert_eq!(child.ft_balance_of(accounts(1)).0, 1_500);
+        assert_eq!(child.ft_total_supply().0, 1_500);
 
-    pub fn set_token_metadata(&mut self, token_id: u64, metadata: String) {
-        assert!(self.token_owners.contains_key(&token_id), "Token does not exist");
-        let mut token_metadata = self.token_metadata.get(&token_id).unwrap_or_default();
-        token_metadata.push(metadata);
-        self.token_metadata.insert(&token_id, &token_metadata);
+        let released = child.burn_for_redeem(accounts(1).into(), U128(250));
+        assert_eq!(released.0, 2);
+        assert_eq!(child.ft_total_supply().0, 1_300);
     }
 
-    pub fn get_token_metadata(&self, token_id: u64) -> Vec<String> {
-        assert!(self.token_owners.contains_key(&token_id), "Token does not exist");
-        self.token_metadata.get(&token_id).unwrap_or_default()
+    #[test]
+    #[should_panic(expected = "Ratio does not match the existing fractions")]
+    fn children_reject_a_different_ratio_while_backed() {
+        let child_account_id = ValidAccountId::try_from("child.token.near").unwrap();
+        testing_env!(context(token_account()).current_account_id(child_account_id).build());
+        let mut child =
+            SelfFractalizedSCTS777::new_child("Child".to_string(), "CHD".to_string(), U128(1_000), U128(100), accounts(1).into());
+        child.mint_backed(accounts(1).into(), U128(5), U128(1));
     }
-}
 
-pub fn get_token_history(&self, token_id: TokenId) -> Vec<Transaction> {
-    let mut history = Vec::new();
-    let mut last_transaction = Transaction {
-        from: env::current_account_id(),
-        to: env::current_account_id(),
-        amount: 0,
-        timestamp: env::block_timestamp(),
-    };
+    #[test]
+    #[should_panic(expected = "The supply must be a multiple of the ratio")]
+    fn child_supplies_must_be_whole_parent_tokens() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        contract.create_child_token("child".to_string(), "Child".to_string(), "CHD".to_string(), U128(1_050), Some(U128(100)));
+    }
 
-    let mut current_owner = self.token_owners.get(&token_id).unwrap_or_default();
-    let mut current_balance = self.token_balances
-        .get(&current_owner)
-        .unwrap_or(&LookupMap::new(format!("Token balances of {}", current_owner).as_bytes().to_vec()))
-        .get(&token_id)
-        .unwrap_or(&0);
+    #[test]
+    fn fractionalize_and_redeem_log_events() {
+        let mut contract = new_fractalized();
+        let child_account_id = "child.token.near".to_string();
+        resolve_with(PromiseResult::Successful(vec![]));
+        assert!(contract.on_child_token_created(child_account_id.clone(), accounts(0).into(), U128(0), U128(0)));
 
-    history.push(last_transaction.clone());
+        testing_env!(context(accounts(0)).build());
+        contract.fractionalize(child_account_id.clone(), U128(10), U128(100));
+        assert_eq!(contract.get_locked_balance(child_account_id.clone()).0, 10);
 
-    if let Some(transfers) = self.token_transfers.get(&token_id) {
-        for transfer in transfers.iter() {
-            let transfer_amount = transfer.amount as u128;
-            let transfer_timestamp = transfer.timestamp;
+        resolve_with(PromiseResult::Successful(vec![]));
+        assert!(contract.on_child_minted(child_account_id.clone(), accounts(0).into(), U128(10), U128(100)));
+        let event = get_logs().pop().unwrap();
+        assert!(event.contains("\"event\":\"fractionalize\""));
+        assert!(event.contains("\"ratio\":\"100\""));
+        assert!(event.contains("\"fractions\":\"1000\""));
 
-            if transfer.from != current_owner {
-                current_balance = self.token_balances
-                    .get(&transfer.from)
-                    .unwrap_or(&LookupMap::new(format!("Token balances of {}", transfer.from).as_bytes().to_vec()))
-                    .get(&token_id)
-                    .unwrap_or(&0);
+        resolve_with(PromiseResult::Successful(b"\"4\"".to_vec()));
+        let released = contract.on_fractions_redeemed(child_account_id.clone(), accounts(0).into(), U128(400));
+        assert_eq!(released.0, 4);
+        assert_eq!(contract.get_locked_balance(child_account_id).0, 6);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 6);
+        let event = get_logs().pop().unwrap();
+        assert!(event.contains("\"event\":\"redeem\""));
+        assert!(event.contains("\"released\":\"4\""));
+    }
 
-                current_owner = transfer.from.clone();
-            }
+    #[test]
+    fn history_keeps_only_the_most_recent_records() {
+        testing_env!(context(accounts(0)).build());
+        let mut history = History::new(b"h".to_vec(), 3);
+        for amount in 1..=5 {
+            history.push(&Transaction::new(None, None, amount, TransactionReason::Mint));
+        }
+        assert_eq!(history.len(), 3);
+        let amounts: Vec<Balance> = paginate(&history, None, None)
+            .into_iter()
+            .map(|record| record.amount.0)
+            .collect();
+        assert_eq!(amounts, vec![3, 4, 5]);
+        let amounts: Vec<Balance> = paginate(&history, Some(U128(1)), Some(1))
+            .into_iter()
+            .map(|record| record.amount.0)
+            .collect();
+        assert_eq!(amounts, vec![4]);
+    }
 
-            let transaction = Transaction {
-                from: transfer.from.clone(),
-                to: transfer.to.clone(),
-                amount: transfer_amount,
-                timestamp: transfer_timestamp,
-            };
+    #[test]
+    fn mul_div_handles_products_above_u128() {
+        assert_eq!(mul_div(u128::MAX, u128::MAX, u128::MAX), Some(u128::MAX));
+        assert_eq!(mul_div(u128::MAX, 3, 2), None);
+        assert_eq!(mul_div(1, 1, 0), None);
+        assert_eq!(mul_div(7, 5, 2), Some(17));
+        let balance = 10u128.pow(30);
+        assert_eq!(mul_div(balance, 3 * TIME_INDEX_SCALE, TIME_INDEX_SCALE), Some(3 * balance));
+    }
 
-            history.push(transaction.clone());
-            last_transaction = transaction.clone();
-        }
+    #[test]
+    fn large_balances_accrue_without_overflow() {
+        testing_env!(context(accounts(0)).block_timestamp(0).build());
+        let total_supply = 10u128.pow(30);
+        let mut contract = SCTS777::new("Fractal".to_string(), "FRC".to_string(), total_supply);
+        contract.set_time_rate(100, U64(1_000)); // 1% per period
+        assert_eq!(contract.ft_balance_at(accounts(0), U64(0)).0, total_supply);
+        assert_eq!(contract.ft_balance_at(accounts(0), U64(1_000)).0, total_supply + total_supply / 100);
     }
 
-    if *current_balance > 0 {
-        let burn_timestamp = env::block_timestamp();
-        let transaction = Transaction {
-            from: current_owner.clone(),
-            to: env::current_account_id(),
-            amount: *current_balance,
-            timestamp: burn_timestamp,
-        };
+    #[test]
+    #[should_panic(expected = "Only the token owner can lock the token")]
+    fn minters_cannot_lock_tokens_they_no_longer_own() {
+        let mut contract = new_scts777();
+        register(&mut contract, accounts(1));
+        let root_token_id = contract.mint_root_token(accounts(1));
+        contract.lock_grandchild_token(root_token_id, U64(1_000));
+    }
+
+    #[test]
+    fn vesting_handles_large_totals_and_records_escrow() {
+        testing_env!(context(accounts(0)).block_timestamp(0).attached_deposit(STORAGE_DEPOSIT).build());
+        let total = 10u128.pow(36);
+        let mut contract = SelfFractalizedSCTS777::new("Fractal".to_string(), "FRC".to_string(), U128(total));
+        let duration = 4 * 365 * 24 * 3_600 * 1_000_000_000u64;
+        let schedule_id = contract.create_vesting_schedule(accounts(1), U128(total), U64(0), U64(0), U64(duration), true);
+        assert_eq!(contract.get_vested_amount(schedule_id, Some(U64(duration / 4))).0, total / 4);
+
+        let history = contract.get_ft_history(None, None);
+        assert_eq!(history.last().unwrap().reason, TransactionReason::Lock);
 
-        history.push(transaction.clone());
-        last_transaction = transaction.clone();
+        testing_env!(context(accounts(1)).block_timestamp(duration / 2).build());
+        assert_eq!(contract.claim(schedule_id).0, total / 2);
+        let history = contract.get_ft_history(None, None);
+        assert_eq!(history.last().unwrap().reason, TransactionReason::Release);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, total / 2);
     }
 
-    history
-}
+    #[test]
+    fn vesting_storage_deposit_is_returned_after_the_final_claim() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        let schedule_id = contract.create_vesting_schedule(accounts(1), U128(100), U64(0), U64(0), U64(1_000), false);
+        let storage_deposit = contract.get_vesting_schedule(schedule_id).unwrap().storage_deposit.0;
+        assert!(storage_deposit > 0);
+        assert_eq!(created_transfers(), vec![(AccountId::from(accounts(0)), STORAGE_DEPOSIT - storage_deposit)]);
 
-    // Pauses all token transfers and functions in the event of a security concern or bug.
-    pub fn pause(&mut self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            env::current_account_id(),
-            "This function can only be called by the contract owner"
-        );
-        
-        self.paused = true;
-        env::log("Token transfers and functions have been paused".as_bytes());
+        testing_env!(context(accounts(1)).block_timestamp(500).build());
+        contract.claim(schedule_id);
+        assert!(created_transfers().is_empty());
+        testing_env!(context(accounts(1)).block_timestamp(1_000).build());
+        contract.claim(schedule_id);
+        assert!(contract.get_vesting_schedule(schedule_id).is_none());
+        assert_eq!(created_transfers(), vec![(AccountId::from(accounts(0)), storage_deposit)]);
     }
 
-    // Resumes all token transfers and functions after a pause.
-    pub fn unpause(&mut self) {
-        assert_eq!(
-            env::predecessor_account_id(),
-            env::current_account_id(),
-            "This function can only be called by the contract owner"
-        );
-        
-        self.paused = false;
-        env::log("Token transfers and functions have been resumed".as_bytes());
+    #[test]
+    fn vesting_storage_deposit_is_returned_on_revoke_once_nothing_is_left_to_claim() {
+        let mut contract = new_fractalized();
+        testing_env!(context(accounts(0)).attached_deposit(STORAGE_DEPOSIT).build());
+        let schedule_id = contract.create_vesting_schedule(accounts(1), U128(100), U64(1_000), U64(0), U64(1_000), true);
+        let storage_deposit = contract.get_vesting_schedule(schedule_id).unwrap().storage_deposit.0;
+
+        testing_env!(context(accounts(0)).build());
+        assert_eq!(contract.revoke_vesting(schedule_id).0, 100);
+        assert!(contract.get_vesting_schedule(schedule_id).is_none());
+        assert_eq!(created_transfers(), vec![(AccountId::from(accounts(0)), storage_deposit)]);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY);
     }
 
-    impl SCTS777 {
-        // Other functions
-        
-        pub fn activate(&mut self, feature: String) -> bool {
-            assert_eq!(
-                env::predecessor_account_id(),
-                env::current_account_id(),
-                "Activation must be called by contract owner"
-            );
-            
-            if !self.active_features.contains(&feature) {
-                self.active_features.push(feature);
-                return true;
-            }
-            
-            false
-        }
-        
-        pub fn deactivate(&mut self, feature: String) -> bool {
-            assert_eq!(
-                env::predecessor_account_id(),
-                env::current_account_id(),
-                "Deactivation must be called by contract owner"
-            );
-            
-            if let Some(pos) = self.active_features.iter().position(|f| f == &feature) {
-                self.active_features.remove(pos);
-                return true;
-            }
-            
-            false
-        }
+    #[test]
+    #[should_panic(expected = "to cover the vesting schedule storage")]
+    fn vesting_schedules_require_a_storage_deposit() {
+        let mut contract = new_fractalized();
+        contract.create_vesting_schedule(accounts(1), U128(100), U64(0), U64(0), U64(1_000), false);
+    }
+
+    #[test]
+    fn migrate_carries_version_1_state_over() {
+        testing_env!(context(token_account()).build());
+        let (alice, bob): (AccountId, AccountId) = (accounts(0).into(), accounts(1).into());
+        let mut v1 = SCTS777V1 {
+            name: "Fractal".to_string(),
+            symbol: "FRC".to_string(),
+            total_supply: 500,
+            balances: LookupMap::new(b"b".to_vec()),
+            allowances: LookupMap::new(b"a".to_vec()),
+            next_token_id: 2,
+            token_owners: LookupMap::new(b"o".to_vec()),
+            token_balances: LookupMap::new(b"t".to_vec()),
+            token_approvals: LookupMap::new(b"p".to_vec()),
+            grandchild_tokens: LookupMap::new(V1_GRANDCHILD_TOKENS_PREFIX.to_vec()),
+        };
+        v1.balances.insert(&alice, &500);
+        v1.token_owners.insert(&0, &alice);
+        v1.token_owners.insert(&1, &bob);
+        let mut v1_children = LookupMap::new(b"g0".to_vec());
+        v1_children.insert(&1, &1);
+        v1.grandchild_tokens.insert(&0, &v1_children);
+        env::state_write(&v1);
+
+        let mut contract = SCTS777::migrate();
+        assert_eq!(contract.get_state_version(), SCTS777_STATE_VERSION);
+        assert_eq!(contract.get_owner(), AccountId::from(token_account()));
+        assert_eq!(contract.ft_total_supply().0, 500);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, 500);
+        assert_eq!(contract.nft_token("0".to_string()).unwrap().owner_id, alice);
+        assert_eq!(contract.nft_token("1".to_string()).unwrap().owner_id, bob);
+        assert!(contract.get_child_tokens(0, None, None).is_empty());
+
+        assert_eq!(contract.migrate_token_owners(0, 10), 2);
+        assert_eq!(contract.nft_total_supply().0, 2);
+        assert_eq!(contract.nft_supply_for_owner(accounts(0)).0, 1);
+        assert_eq!(contract.get_tokens_for_owner(accounts(0), None, None), vec![0]);
+        assert_eq!(contract.get_tokens_for_owner(accounts(1), None, None), vec![1]);
+        assert_eq!(contract.get_token_metadata(0).unwrap().parent_token_id, None);
+        assert_eq!(contract.migrate_token_owners(0, 10), 0);
+
+        assert_eq!(contract.migrate_grandchild_tokens(vec![(0, vec![1])]), 1);
+        assert_eq!(contract.get_child_tokens(0, None, None), vec![1]);
+        assert_eq!(contract.get_tokens_for_owner(accounts(1), None, None), vec![1]);
+        assert_eq!(contract.get_token_metadata(1).unwrap().parent_token_id, Some(0));
+        // Resubmitting the same links indexes nothing
+        assert_eq!(contract.migrate_grandchild_tokens(vec![(0, vec![1])]), 0);
+        assert_eq!(contract.nft_total_supply().0, 2);
+        let token_ids: Vec<String> = contract.nft_tokens(None, None).into_iter().map(|token| token.token_id).collect();
+        assert_eq!(token_ids, vec!["0".to_string(), "1".to_string()]);
     }
+}
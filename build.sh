@@ -0,0 +1,14 @@
+#!/bin/bash
+# Builds each contract to its own Wasm in res/, which the sandbox tests deploy:
+# SelfFractalizedSCTS777 to res/self_fractalization.wasm and SCTS777 to res/scts777.wasm.
+set -e
+cd "$(dirname "$0")"
+
+export RUSTFLAGS='-C link-arg=-s -C target-cpu=mvp'
+mkdir -p res
+build() {
+    cargo build --target wasm32-unknown-unknown --release --no-default-features --features "$1"
+    cp target/wasm32-unknown-unknown/release/self_fractalization.wasm "res/$2"
+}
+build self-fractalized self_fractalization.wasm
+build scts777 scts777.wasm
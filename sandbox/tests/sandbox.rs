@@ -0,0 +1,195 @@
+// Sandbox tests against the compiled SelfFractalizedSCTS777 contract.
+// Run build.sh in the repository root first; the tests deploy the contract from WASM_PATH.
+use near_workspaces::types::NearToken;
+use near_workspaces::{Account, Contract};
+use serde_json::json;
+
+const WASM_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../res/self_fractalization.wasm");
+const TOTAL_SUPPLY: u128 = 1_000_000;
+
+async fn deploy_fractalized(worker: &near_workspaces::Worker<near_workspaces::network::Sandbox>) -> anyhow::Result<(Contract, Account)> {
+    let wasm = std::fs::read(WASM_PATH)?;
+    let contract = worker.dev_deploy(&wasm).await?;
+    let owner = worker.dev_create_account().await?;
+    owner
+        .call(contract.id(), "new")
+        .args_json(json!({ "name": "Fractal", "symbol": "FRC", "total_supply": TOTAL_SUPPLY.to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    Ok((contract, owner))
+}
+
+async fn ft_balance_of(contract: &Contract, account: &Account) -> anyhow::Result<u128> {
+    let balance: String = contract
+        .view("ft_balance_of")
+        .args_json(json!({ "account_id": account.id() }))
+        .await?
+        .json()?;
+    Ok(balance.parse()?)
+}
+
+#[tokio::test]
+async fn ft_transfer_and_metadata() -> anyhow::Result<()> {
+    let worker = near_workspaces::sandbox().await?;
+    let (contract, owner) = deploy_fractalized(&worker).await?;
+    let alice = worker.dev_create_account().await?;
+
+    owner
+        .call(contract.id(), "ft_transfer")
+        .args_json(json!({ "receiver_id": alice.id(), "amount": "250" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .transact()
+        .await?
+        .into_result()?;
+    assert_eq!(ft_balance_of(&contract, &owner).await?, TOTAL_SUPPLY - 250);
+    assert_eq!(ft_balance_of(&contract, &alice).await?, 250);
+
+    let metadata: serde_json::Value = contract.view("ft_metadata").await?.json()?;
+    assert_eq!(metadata["spec"], "ft-1.0.0");
+    assert_eq!(metadata["symbol"], "FRC");
+    Ok(())
+}
+
+#[tokio::test]
+async fn ft_transfer_call_refunds_when_the_receiver_has_no_contract() -> anyhow::Result<()> {
+    let worker = near_workspaces::sandbox().await?;
+    let (contract, owner) = deploy_fractalized(&worker).await?;
+    let alice = worker.dev_create_account().await?;
+
+    // ft_on_transfer fails on an account without code, so ft_resolve_transfer returns everything
+    let used: String = owner
+        .call(contract.id(), "ft_transfer_call")
+        .args_json(json!({ "receiver_id": alice.id(), "amount": "500", "msg": "" }))
+        .deposit(NearToken::from_yoctonear(1))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(used, "0");
+    assert_eq!(ft_balance_of(&contract, &owner).await?, TOTAL_SUPPLY);
+    assert_eq!(ft_balance_of(&contract, &alice).await?, 0);
+    Ok(())
+}
+
+// Deploys the parent to a funded account with the child code set, and initializes it as `owner`
+async fn deploy_parent(
+    worker: &near_workspaces::Worker<near_workspaces::network::Sandbox>,
+) -> anyhow::Result<(Account, Contract, Account)> {
+    let wasm = std::fs::read(WASM_PATH)?;
+    let root = worker.root_account()?;
+    let parent = root
+        .create_subaccount("parent")
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?;
+    let contract = parent.deploy(&wasm).await?.into_result()?;
+    let owner = root
+        .create_subaccount("owner")
+        .initial_balance(NearToken::from_near(50))
+        .transact()
+        .await?
+        .into_result()?;
+    owner
+        .call(contract.id(), "new")
+        .args_json(json!({ "name": "Fractal", "symbol": "FRC", "total_supply": TOTAL_SUPPLY.to_string() }))
+        .transact()
+        .await?
+        .into_result()?;
+    owner
+        .call(contract.id(), "set_child_code")
+        .args(wasm)
+        .max_gas()
+        .transact()
+        .await?
+        .into_result()?;
+    Ok((parent, contract, owner))
+}
+
+async fn create_child_token(contract: &Contract, owner: &Account, prefix: &str) -> anyhow::Result<bool> {
+    Ok(owner
+        .call(contract.id(), "create_child_token")
+        .args_json(json!({ "prefix": prefix, "name": "Child", "symbol": "CHD", "total_supply": "1000" }))
+        .deposit(NearToken::from_near(10))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?)
+}
+
+#[tokio::test]
+async fn create_child_token_deploys_and_registers_the_child() -> anyhow::Result<()> {
+    let worker = near_workspaces::sandbox().await?;
+    let (_parent, contract, owner) = deploy_parent(&worker).await?;
+
+    assert!(create_child_token(&contract, &owner, "child").await?);
+    let child_account_id = format!("child.{}", contract.id());
+    let count: u64 = contract.view("get_child_token_count").await?.json()?;
+    assert_eq!(count, 1);
+    let locked: String = contract
+        .view("get_locked_balance")
+        .args_json(json!({ "child_account_id": child_account_id }))
+        .await?
+        .json()?;
+    assert_eq!(locked, "1000");
+    assert_eq!(ft_balance_of(&contract, &owner).await?, TOTAL_SUPPLY - 1_000);
+
+    let child_supply: String = worker
+        .view(&child_account_id.parse::<near_workspaces::AccountId>()?, "ft_total_supply")
+        .await?
+        .json()?;
+    assert_eq!(child_supply, "1000");
+    Ok(())
+}
+
+#[tokio::test]
+async fn create_child_token_refunds_when_deployment_fails() -> anyhow::Result<()> {
+    let worker = near_workspaces::sandbox().await?;
+    let (parent, contract, owner) = deploy_parent(&worker).await?;
+
+    // The child account already exists, so creating it fails
+    parent
+        .create_subaccount("taken")
+        .initial_balance(NearToken::from_near(1))
+        .transact()
+        .await?
+        .into_result()?;
+    let balance_before = owner.view_account().await?.balance;
+
+    assert!(!create_child_token(&contract, &owner, "taken").await?);
+    let count: u64 = contract.view("get_child_token_count").await?.json()?;
+    assert_eq!(count, 0);
+    assert_eq!(ft_balance_of(&contract, &owner).await?, TOTAL_SUPPLY);
+
+    // Only gas is spent; the 10 NEAR deposit comes back
+    let spent = balance_before.as_yoctonear() - owner.view_account().await?.balance.as_yoctonear();
+    assert!(spent < NearToken::from_near(1).as_yoctonear());
+    Ok(())
+}
+
+#[tokio::test]
+async fn creator_can_redeem_the_child_supply() -> anyhow::Result<()> {
+    let worker = near_workspaces::sandbox().await?;
+    let (_parent, contract, owner) = deploy_parent(&worker).await?;
+    assert!(create_child_token(&contract, &owner, "child").await?);
+    let child_account_id = format!("child.{}", contract.id());
+
+    let child_balance: String = worker
+        .view(&child_account_id.parse::<near_workspaces::AccountId>()?, "ft_balance_of")
+        .args_json(json!({ "account_id": owner.id() }))
+        .await?
+        .json()?;
+    assert_eq!(child_balance, "1000");
+
+    let released: String = owner
+        .call(contract.id(), "redeem")
+        .args_json(json!({ "child_account_id": child_account_id, "amount": "400" }))
+        .max_gas()
+        .transact()
+        .await?
+        .json()?;
+    assert_eq!(released, "400");
+    assert_eq!(ft_balance_of(&contract, &owner).await?, TOTAL_SUPPLY - 600);
+    Ok(())
+}